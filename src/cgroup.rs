@@ -0,0 +1,272 @@
+use lazy_static::lazy_static;
+use nix::unistd::{access, AccessFlags};
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+
+use crate::config::ProcessConfig;
+use crate::log;
+
+/// Sub-group multip moves itself into. Processes are not allowed in the
+/// delegated group itself once the controllers are enabled for its children.
+const SELF_GROUP: &str = "multip";
+
+const CONTROLLERS: [&str; 3] = ["memory", "cpu", "pids"];
+
+lazy_static! {
+    /// Found before multip moves itself to the sub-group
+    static ref DIR: Result<(PathBuf, bool), String> = find_dir();
+    static ref ROOT: Result<Cgroups, String> = Cgroups::setup();
+}
+
+/// The delegated cgroup v2 subtree multip was started in
+pub struct Cgroups {
+    dir: PathBuf,
+}
+
+/// Sub-group of a single process
+pub struct ChildCgroup {
+    dir: PathBuf,
+}
+
+/// Find the cgroup2 mount point from /proc/self/mountinfo. Returns the mount
+/// point and the root of the hierarchy it shows.
+fn parse_mountinfo(mountinfo: &str) -> Option<(&str, &str)> {
+    mountinfo.lines().find_map(|line| {
+        let mut sides = line.splitn(2, " - ");
+        let mount: Vec<&str> = sides.next()?.split(' ').collect();
+        let fstype = sides.next()?.split(' ').next()?;
+
+        if fstype == "cgroup2" && mount.len() > 4 {
+            Some((mount[4], mount[3]))
+        } else {
+            None
+        }
+    })
+}
+
+/// The cgroup v2 path of the process from /proc/self/cgroup
+fn parse_proc_cgroup(cgroup: &str) -> Option<&str> {
+    cgroup.lines().find_map(|line| line.strip_prefix("0::"))
+}
+
+/// Count of the OOM kills from memory.events
+fn parse_oom_kills(events: &str) -> u64 {
+    events
+        .lines()
+        .find_map(|line| line.strip_prefix("oom_kill "))
+        .and_then(|count| count.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+fn write_file(path: &Path, content: &str) -> Result<(), String> {
+    fs::write(path, content)
+        .map_err(|err| format!("Failed to write {} to {}: {}", content, path.display(), err))
+}
+
+/// The cgroup multip was started in and whether it is the root of the
+/// hierarchy
+fn find_dir() -> Result<(PathBuf, bool), String> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")
+        .map_err(|err| format!("Failed to read /proc/self/mountinfo: {}", err))?;
+    let cgroup = fs::read_to_string("/proc/self/cgroup")
+        .map_err(|err| format!("Failed to read /proc/self/cgroup: {}", err))?;
+
+    let (mount_point, mount_root) =
+        parse_mountinfo(&mountinfo).ok_or("cgroup v2 is not mounted")?;
+    let path = parse_proc_cgroup(&cgroup).ok_or("Not running in a cgroup v2 hierarchy")?;
+    let path = path.strip_prefix(mount_root).unwrap_or(path);
+    let path = path.trim_start_matches('/');
+
+    Ok((Path::new(mount_point).join(path), path.is_empty()))
+}
+
+/// Move all processes of the group to another group. The processes which
+/// exit meanwhile are skipped.
+fn move_procs(from: &Path, to: &Path) -> Result<(), String> {
+    let path = from.join("cgroup.procs");
+    let procs = fs::read_to_string(&path)
+        .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+
+    for pid in procs.lines() {
+        fs::write(to.join("cgroup.procs"), pid).unwrap_or(());
+    }
+
+    Ok(())
+}
+
+impl Cgroups {
+    fn setup() -> Result<Cgroups, String> {
+        let (dir, is_root) = DIR.clone()?;
+
+        let self_dir = dir.join(SELF_GROUP);
+        if !self_dir.exists() {
+            fs::create_dir(&self_dir)
+                .map_err(|err| format!("Failed to create {}: {}", self_dir.display(), err))?;
+        }
+        write_file(&self_dir.join("cgroup.procs"), "0")?;
+
+        // The processes started before the first process with limits, when
+        // it was added by reloading the config, are in the group too. The
+        // root group is exempt from the rule and has all processes of the
+        // system.
+        if !is_root {
+            move_procs(&dir, &self_dir)?;
+        }
+
+        let available = fs::read_to_string(dir.join("cgroup.controllers")).unwrap_or_default();
+        let enable: Vec<String> = CONTROLLERS
+            .iter()
+            .filter(|&&controller| available.split_whitespace().any(|c| c == controller))
+            .map(|controller| format!("+{}", controller))
+            .collect();
+
+        if !enable.is_empty() {
+            write_file(&dir.join("cgroup.subtree_control"), &enable.join(" "))?;
+        }
+
+        log!("Using cgroup {}", dir.display());
+
+        Ok(Cgroups { dir })
+    }
+
+    /// Create or update the sub-group of the process
+    pub fn create(&self, config: &ProcessConfig) -> Result<ChildCgroup, String> {
+        let dir = self.dir.join(&config.name);
+        let created = !dir.exists();
+
+        if created {
+            fs::create_dir(&dir)
+                .map_err(|err| format!("Failed to create {}: {}", dir.display(), err))?;
+        }
+
+        let cgroup = ChildCgroup { dir };
+        let res = self.write_limits(&cgroup, config);
+
+        // Do not leave the group behind when the config is rejected
+        if res.is_err() && created {
+            cgroup.remove();
+        }
+
+        res.map(|_| cgroup)
+    }
+
+    fn write_limits(&self, cgroup: &ChildCgroup, config: &ProcessConfig) -> Result<(), String> {
+        // Reset the removed limits too when the config is reloaded
+        let limits = [
+            ("memory.max", config.memory_max.clone()),
+            ("cpu.max", config.cpu_max.clone()),
+            ("pids.max", config.pids_max.map(|max| max.to_string())),
+        ];
+
+        for (file, limit) in limits.iter() {
+            let path = cgroup.dir.join(file);
+
+            match limit {
+                Some(_) if !path.exists() => {
+                    return Err(format!(
+                        "Cannot set {} for [{}]: the controller is not enabled in {}",
+                        file,
+                        config.name,
+                        self.dir.display()
+                    ));
+                }
+                Some(limit) => write_file(&path, limit)?,
+                None if path.exists() => write_file(&path, "max")?,
+                None => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Check that the limits of the process can be applied without changing
+/// anything. The sub-group is created and the limits written when the
+/// process is started.
+pub fn check(config: &ProcessConfig) -> Result<(), String> {
+    let (dir, _) = DIR.as_ref().map_err(|err| err.clone())?;
+
+    access(dir, AccessFlags::W_OK)
+        .map_err(|err| format!("Cannot create cgroups in {}: {}", dir.display(), err))?;
+
+    let available = fs::read_to_string(dir.join("cgroup.controllers")).unwrap_or_default();
+    let limits = [
+        ("memory", config.memory_max.is_some()),
+        ("cpu", config.cpu_max.is_some()),
+        ("pids", config.pids_max.is_some()),
+    ];
+
+    for (controller, _) in limits.iter().filter(|(_, set)| *set) {
+        if !available.split_whitespace().any(|c| c == *controller) {
+            return Err(format!(
+                "Cannot set {}.max for [{}]: the controller is not available in {}",
+                controller,
+                config.name,
+                dir.display()
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// The cgroups of the processes. Set up on the first use.
+pub fn root() -> Result<&'static Cgroups, String> {
+    ROOT.as_ref().map_err(|err| err.clone())
+}
+
+impl ChildCgroup {
+    /// Opened before the process is started so the child only has to write
+    /// to it before exec
+    pub fn procs_file(&self) -> std::io::Result<File> {
+        OpenOptions::new()
+            .write(true)
+            .open(self.dir.join("cgroup.procs"))
+    }
+
+    pub fn oom_kills(&self) -> u64 {
+        fs::read_to_string(self.dir.join("memory.events"))
+            .map(|events| parse_oom_kills(&events))
+            .unwrap_or(0)
+    }
+
+    /// Remove the sub-group once the process is gone for good
+    pub fn remove(&self) {
+        if let Err(err) = fs::remove_dir(&self.dir) {
+            log!("Failed to remove {}: {}", self.dir.display(), err);
+        }
+    }
+}
+
+#[test]
+fn finds_cgroup2_mount() {
+    let mountinfo = "\
+25 30 0:23 / /sys rw,nosuid - sysfs sysfs rw
+42 32 0:38 / /sys/fs/cgroup/unified rw,relatime shared:9 - cgroup2 cgroup2 rw
+";
+
+    assert_eq!(
+        parse_mountinfo(mountinfo),
+        Some(("/sys/fs/cgroup/unified", "/"))
+    );
+    assert_eq!(
+        parse_mountinfo("25 30 0:23 / /sys rw - sysfs sysfs rw"),
+        None
+    );
+}
+
+#[test]
+fn finds_cgroup2_path() {
+    assert_eq!(
+        parse_proc_cgroup("4:memory:/docker/abc\n0::/docker/abc\n"),
+        Some("/docker/abc")
+    );
+    assert_eq!(parse_proc_cgroup("4:memory:/docker/abc\n"), None);
+}
+
+#[test]
+fn parses_oom_kills() {
+    let events = "low 0\nhigh 0\nmax 12\noom 2\noom_kill 1\n";
+    assert_eq!(parse_oom_kills(events), 1);
+    assert_eq!(parse_oom_kills(""), 0);
+}
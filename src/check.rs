@@ -0,0 +1,177 @@
+use std::env;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use crate::config::{self, ProcessConfig};
+use crate::OutputRules;
+
+const USAGE: &str = "usage: multip check [--config FILE] [name: command...]";
+
+/// Commands run by /bin/sh itself
+const SHELL_BUILTINS: [&str; 18] = [
+    ".", ":", "[", "cd", "echo", "eval", "exec", "exit", "export", "false", "read", "set", "test",
+    "trap", "true", "ulimit", "umask", "wait",
+];
+
+/// The program started by the shell command. Skips the leading variable
+/// assignments and the `exec` and `env` wrappers. Returns None when it
+/// depends on the shell expansions.
+fn program(command: &str) -> Option<&str> {
+    let mut words = command.split_whitespace();
+    let mut wrapped = false;
+
+    let word = loop {
+        let word = words.next()?;
+
+        if word.contains('=') && !word.starts_with('=') {
+            continue;
+        }
+
+        match word {
+            "exec" | "env" => wrapped = true,
+            // Options of the wrappers like `env -i`
+            _ if wrapped && word.starts_with('-') => {}
+            _ => break word,
+        }
+    };
+    let word = word.trim_matches(['\'', '"']);
+
+    if word.contains(['$', '`', '*', '?']) {
+        None
+    } else {
+        Some(word)
+    }
+}
+
+fn is_executable(path: &Path) -> bool {
+    fs::metadata(path)
+        .map(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+fn check_command(command: &str) -> Result<(), String> {
+    let program = match program(command) {
+        Some(program) => program,
+        None => return Ok(()),
+    };
+
+    if SHELL_BUILTINS.contains(&program) {
+        return Ok(());
+    }
+
+    if program.contains('/') {
+        return if is_executable(Path::new(program)) {
+            Ok(())
+        } else {
+            Err(format!("{} is not an executable file", program))
+        };
+    }
+
+    let path = env::var("PATH").unwrap_or_default();
+    if env::split_paths(&path).any(|dir| is_executable(&dir.join(program))) {
+        Ok(())
+    } else {
+        Err(format!("{} not found in PATH", program))
+    }
+}
+
+fn check_process(process: &ProcessConfig) -> Result<(), String> {
+    process.validate()?;
+    OutputRules::new(process)?;
+    check_command(&process.command)
+        .map_err(|err| format!("Invalid command for [{}]: {}", process.name, err))
+}
+
+/// The `multip check` subcommand. Validates the processes without starting
+/// them and returns the exit code.
+pub fn run(args: &[String]) -> i32 {
+    let mut processes = Vec::new();
+    let mut cli_commands = Vec::new();
+    let mut args_iter = args.iter();
+
+    while let Some(arg) = args_iter.next() {
+        if arg == "--config" {
+            let path = match args_iter.next() {
+                Some(path) => path,
+                None => {
+                    eprintln!("--config requires a file path\n\n{}", USAGE);
+                    return 2;
+                }
+            };
+
+            match config::load(path) {
+                Ok(config) => processes.extend(config.process),
+                Err(err) => {
+                    eprintln!("{}", err);
+                    return 1;
+                }
+            }
+        } else {
+            cli_commands.push(arg);
+        }
+    }
+
+    processes.extend(ProcessConfig::from_cli(&cli_commands));
+
+    if processes.is_empty() {
+        eprintln!("No processes to check\n\n{}", USAGE);
+        return 2;
+    }
+
+    let mut problems = 0;
+    let mut names: Vec<String> = Vec::new();
+
+    for process in processes.iter() {
+        let instances = config::expand_instances(vec![process.clone()]);
+        if let Some(dup) = instances.iter().find(|p| names.contains(&p.name)) {
+            println!("Duplicate process name [{}]", dup.name);
+            problems += 1;
+            continue;
+        }
+        names.extend(instances.into_iter().map(|p| p.name));
+
+        match check_process(process) {
+            Ok(()) => println!("[{}] {}", process.name, process.command),
+            Err(err) => {
+                println!("{}", err);
+                problems += 1;
+            }
+        }
+    }
+
+    if problems > 0 {
+        println!(
+            "Found {} problems in {} processes",
+            problems,
+            processes.len()
+        );
+        1
+    } else {
+        println!("All {} processes are valid", processes.len());
+        0
+    }
+}
+
+#[test]
+fn finds_program() {
+    assert_eq!(program("nginx -g 'daemon off;'"), Some("nginx"));
+    assert_eq!(program("FOO=1 BAR=2 /app/server"), Some("/app/server"));
+    assert_eq!(program("'/app/server' --port 80"), Some("/app/server"));
+    assert_eq!(program("exec sleep 1"), Some("sleep"));
+    assert_eq!(program("env FOO=1 ./server"), Some("./server"));
+    assert_eq!(program("FOO=1 exec env -i BAR=2 nginx"), Some("nginx"));
+    assert_eq!(program("exec"), None);
+    assert_eq!(program("$HOME/bin/server"), None);
+    assert_eq!(program(""), None);
+}
+
+#[test]
+fn checks_commands() {
+    assert!(check_command("sh -c 'echo hi'").is_ok());
+    assert!(check_command("exec sleep 1").is_ok());
+    assert!(check_command("/bin/sh").is_ok());
+    assert!(check_command("/etc/passwd").is_err());
+    assert!(check_command("surely-not-a-command-1234").is_err());
+    assert!(check_command("exec surely-not-a-command-1234").is_err());
+}
@@ -0,0 +1,778 @@
+use nix::sys::signal::Signal;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+use crate::cron::Schedule;
+
+/// Contents of the file given with `--config`
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub process: Vec<ProcessConfig>,
+}
+
+#[derive(Deserialize, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ProcessConfig {
+    pub name: String,
+    pub command: String,
+
+    /// When not empty only the lines matching at least one of these are printed
+    #[serde(default)]
+    pub include: Vec<String>,
+
+    /// Lines matching any of these are dropped
+    #[serde(default)]
+    pub exclude: Vec<String>,
+
+    #[serde(default)]
+    pub redact: Vec<RedactConfig>,
+
+    pub multiline: Option<MultilineConfig>,
+
+    #[serde(default)]
+    pub invalid_utf8: InvalidUtf8,
+
+    /// Defaults to the MULTIP_MAX_LINE_LENGTH env
+    pub max_line_length: Option<usize>,
+
+    #[serde(default)]
+    pub long_lines: LongLines,
+
+    /// Hard limit for the line length with `long_lines = "unlimited"`.
+    /// Defaults to 1MiB.
+    pub line_memory_limit: Option<usize>,
+
+    /// Print incomplete lines (prompts etc.) after this long without output
+    pub idle_flush_ms: Option<u64>,
+
+    /// Skip the line processing and `[name]` prefixes
+    pub passthrough: Option<Passthrough>,
+
+    /// What to do when the output buffer (MULTIP_BUFFER_LINES) is full
+    #[serde(default)]
+    pub buffer_full: BufferFull,
+
+    /// Signal used to stop the process. Defaults to SIGTERM.
+    pub stop_signal: Option<String>,
+
+    /// SIGKILL the process if it has not stopped within this time after the
+    /// stop signal. Defaults to 10 seconds.
+    pub stop_timeout_ms: Option<u64>,
+
+    /// Forward the parent signals as other signals or `ignore` them. The
+    /// signals not listed here are forwarded as is.
+    #[serde(default)]
+    pub signals: BTreeMap<String, String>,
+
+    /// Start the process in its own process group and send the signals to
+    /// the whole group
+    #[serde(default)]
+    pub process_group: bool,
+
+    /// cgroup v2 `memory.max` such as "512M"
+    pub memory_max: Option<String>,
+
+    /// cgroup v2 `cpu.max` such as "50000 100000" for half a CPU
+    pub cpu_max: Option<String>,
+
+    /// cgroup v2 `pids.max`
+    pub pids_max: Option<u64>,
+
+    /// The process is ready once it prints a line matching this. Without it
+    /// the process is ready as soon as it is running.
+    pub ready_pattern: Option<String>,
+
+    /// Run this many copies named `name.1`, `name.2` and so on
+    pub instances: Option<u32>,
+
+    /// Set PORT to this plus the instance number minus one for each instance
+    pub base_port: Option<u16>,
+
+    /// Number of the copy when running multiple instances
+    #[serde(skip)]
+    pub instance: Option<u32>,
+
+    /// Run the process on this cron schedule instead of keeping it running
+    pub schedule: Option<String>,
+
+    /// What to do when a scheduled run is still running at the next time
+    #[serde(default)]
+    pub overlap: Overlap,
+
+    /// Restart the process when these files change
+    pub watch: Option<WatchConfig>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum BufferFull {
+    /// Stop reading the output which eventually blocks the process writes
+    #[default]
+    Block,
+    DropOldest,
+    DropNewest,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Passthrough {
+    /// Copy the output byte-for-byte to the multip stdout and stderr
+    Copy,
+    /// Let the process write directly to the multip stdout and stderr
+    Inherit,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Overlap {
+    /// Let the previous run finish and skip this one
+    #[default]
+    Skip,
+    /// Stop the previous run and start again
+    Restart,
+}
+
+/// What to do with lines longer than the max line length
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LongLines {
+    /// Print the line in multiple parts
+    #[default]
+    Split,
+    /// Drop the rest of the line
+    Truncate,
+    /// Split only when the line exceeds the line_memory_limit
+    Unlimited,
+}
+
+/// What to do with output that is not valid UTF-8
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum InvalidUtf8 {
+    /// Print an error instead of the line
+    #[default]
+    Error,
+    /// Use the U+FFFD replacement character for the invalid bytes
+    Lossy,
+    /// Print the invalid bytes as `\xNN`
+    Escape,
+    /// Pass the bytes through as is
+    Raw,
+}
+
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RedactConfig {
+    pub pattern: String,
+    #[serde(default = "default_redact_replace")]
+    pub replace: String,
+}
+
+fn default_redact_replace() -> String {
+    String::from("***")
+}
+
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MultilineConfig {
+    /// Lines matching this are joined with the preceding line
+    pub pattern: String,
+    pub separator: String,
+    /// Emit the event when no continuation line arrives within this time
+    pub flush_timeout_ms: u64,
+    pub max_lines: usize,
+}
+
+impl Default for MultilineConfig {
+    fn default() -> Self {
+        MultilineConfig {
+            pattern: String::from(r"^\s"),
+            separator: String::from("\n"),
+            flush_timeout_ms: 200,
+            max_lines: 500,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct WatchConfig {
+    /// Glob patterns such as `src/**/*.py` relative to the working directory
+    pub paths: Vec<String>,
+    /// Changes to the files matching these are ignored
+    #[serde(default)]
+    pub ignore: Vec<String>,
+    /// Restart once the files have not changed for this long
+    #[serde(default = "default_watch_debounce_ms")]
+    pub debounce_ms: u64,
+    /// Restart all the processes instead of only this one
+    #[serde(default)]
+    pub restart_all: bool,
+}
+
+fn default_watch_debounce_ms() -> u64 {
+    300
+}
+
+/// Parse signal names such as `SIGHUP` and `HUP`
+pub fn parse_signal(name: &str) -> Result<Signal, String> {
+    let name = name.trim().to_uppercase();
+    let name = if name.starts_with("SIG") {
+        name
+    } else {
+        format!("SIG{}", name)
+    };
+
+    Signal::from_str(&name).map_err(|_| format!("Unknown signal {}", name))
+}
+
+impl ProcessConfig {
+    pub fn stop_signal(&self) -> Signal {
+        self.stop_signal
+            .as_deref()
+            .and_then(|name| parse_signal(name).ok())
+            .unwrap_or(Signal::SIGTERM)
+    }
+
+    pub fn stop_timeout(&self) -> Duration {
+        Duration::from_millis(self.stop_timeout_ms.unwrap_or(10_000))
+    }
+
+    /// Check the options which are not validated by the config parser
+    pub fn validate(&self) -> Result<(), String> {
+        if !is_valid_name(&self.name) {
+            return Err(format!(
+                "Invalid process name \"{}\": use only letters, digits, '_', '-' and '.'",
+                self.name
+            ));
+        }
+
+        if self.command.trim().is_empty() {
+            return Err(format!("Empty command for [{}]", self.name));
+        }
+
+        for (option, value) in [
+            ("max_line_length", self.max_line_length),
+            ("line_memory_limit", self.line_memory_limit),
+        ] {
+            if value == Some(0) {
+                return Err(format!(
+                    "Invalid {} for [{}]: must be at least 1",
+                    option, self.name
+                ));
+            }
+        }
+
+        if let Some(instances) = self.instances {
+            if instances == 0 || instances > MAX_INSTANCES {
+                return Err(format!(
+                    "Invalid instances for [{}]: must be from 1 to {}",
+                    self.name, MAX_INSTANCES
+                ));
+            }
+        }
+
+        if let Some(base_port) = self.base_port {
+            let instances = self
+                .instances
+                .ok_or_else(|| format!("base_port for [{}] requires instances", self.name))?;
+
+            let last_port = u32::from(base_port).checked_add(instances - 1);
+            if last_port.is_none_or(|port| port > u32::from(u16::MAX)) {
+                return Err(format!(
+                    "Invalid base_port for [{}]: the ports of the instances exceed {}",
+                    self.name,
+                    u16::MAX
+                ));
+            }
+        }
+
+        if let Some(name) = &self.stop_signal {
+            parse_signal(name)
+                .map_err(|err| format!("Invalid stop_signal for [{}]: {}", self.name, err))?;
+        }
+
+        for (from, to) in self.signals.iter() {
+            let from = parse_signal(from)
+                .map_err(|err| format!("Invalid signals for [{}]: {}", self.name, err))?;
+
+            if !is_routable(from) {
+                return Err(format!(
+                    "Invalid signals for [{}]: {} cannot be routed",
+                    self.name, from
+                ));
+            }
+
+            if to != "ignore" {
+                parse_signal(to)
+                    .map_err(|err| format!("Invalid signals for [{}]: {}", self.name, err))?;
+            }
+        }
+
+        if let Some(schedule) = &self.schedule {
+            Schedule::parse(schedule)
+                .and_then(|schedule| {
+                    schedule
+                        .next_after(SystemTime::now())
+                        .ok_or_else(|| String::from("it never matches"))
+                })
+                .map_err(|err| format!("Invalid schedule for [{}]: {}", self.name, err))?;
+        }
+
+        if let Some(watch) = &self.watch {
+            if watch.paths.is_empty() {
+                return Err(format!(
+                    "Invalid watch for [{}]: no paths to watch",
+                    self.name
+                ));
+            }
+
+            if self.is_job() {
+                return Err(format!(
+                    "Invalid watch for [{}]: scheduled jobs are not restarted",
+                    self.name
+                ));
+            }
+        }
+
+        if self.passthrough.is_some() {
+            if let Some(option) = self.line_options().first() {
+                return Err(format!(
+                    "Invalid {} for [{}]: the output of passthrough processes is not read",
+                    option, self.name
+                ));
+            }
+        }
+
+        if let Some(memory_max) = &self.memory_max {
+            if !is_valid_memory_max(memory_max) {
+                return Err(format!(
+                    "Invalid memory_max for [{}]: use bytes with an optional K, M, G or T suffix or \"max\"",
+                    self.name
+                ));
+            }
+        }
+
+        if let Some(cpu_max) = &self.cpu_max {
+            if !is_valid_cpu_max(cpu_max) {
+                return Err(format!(
+                    "Invalid cpu_max for [{}]: use \"QUOTA PERIOD\" in microseconds or \"max\"",
+                    self.name
+                ));
+            }
+        }
+
+        if self.has_cgroup_limits() && self.name == "multip" {
+            return Err(format!(
+                "Invalid name for [{}]: cannot be used as a cgroup name",
+                self.name
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// The options set for processing the output lines. None of them apply
+    /// to the passthrough processes.
+    fn line_options(&self) -> Vec<&'static str> {
+        let default = ProcessConfig::default();
+        let options = [
+            ("include", !self.include.is_empty()),
+            ("exclude", !self.exclude.is_empty()),
+            ("redact", !self.redact.is_empty()),
+            ("multiline", self.multiline.is_some()),
+            ("invalid_utf8", self.invalid_utf8 != default.invalid_utf8),
+            ("max_line_length", self.max_line_length.is_some()),
+            ("long_lines", self.long_lines != default.long_lines),
+            ("line_memory_limit", self.line_memory_limit.is_some()),
+            ("idle_flush_ms", self.idle_flush_ms.is_some()),
+            ("buffer_full", self.buffer_full != default.buffer_full),
+            ("ready_pattern", self.ready_pattern.is_some()),
+        ];
+
+        options
+            .iter()
+            .filter(|(_, set)| *set)
+            .map(|(option, _)| *option)
+            .collect()
+    }
+
+    /// Is the process run on a schedule
+    pub fn is_job(&self) -> bool {
+        self.schedule.is_some()
+    }
+
+    /// Is the process started in its own cgroup
+    pub fn has_cgroup_limits(&self) -> bool {
+        self.memory_max.is_some() || self.cpu_max.is_some() || self.pids_max.is_some()
+    }
+
+    /// Is there an explicit rule for the signal
+    pub fn has_signal_route(&self, sig: Signal) -> bool {
+        self.signals
+            .keys()
+            .any(|from| parse_signal(from) == Ok(sig))
+    }
+
+    /// The signal to send to the process when multip gets the given signal or
+    /// None if it is ignored
+    pub fn route_signal(&self, sig: Signal) -> Option<Signal> {
+        if !is_routable(sig) {
+            return Some(sig);
+        }
+
+        let route = self
+            .signals
+            .iter()
+            .find(|(from, _)| parse_signal(from) == Ok(sig));
+
+        match route {
+            None if is_opt_in(sig) => None,
+            None => Some(sig),
+            Some((_, to)) if to == "ignore" => None,
+            Some((_, to)) => parse_signal(to).ok(),
+        }
+    }
+
+    /// The signal sent when multip brings all the processes down with
+    /// `sig`. The stop_signal when set and otherwise `sig` through the
+    /// routing rules. Ignoring it would leave the process running.
+    pub fn shutdown_signal(&self, sig: Signal) -> Signal {
+        if self.stop_signal.is_some() {
+            return self.stop_signal();
+        }
+
+        self.route_signal(sig).unwrap_or(sig)
+    }
+
+    /// The PORT of the instance
+    pub fn port(&self) -> Option<u16> {
+        Some(self.base_port? + (self.instance? - 1) as u16)
+    }
+
+    /// Parse the `name: command` forms used on the command line. Commands
+    /// without a name are named `cmd1`, `cmd2` and so on and `name*N`
+    /// starts N instances.
+    pub fn from_cli<S: AsRef<str>>(args: &[S]) -> Vec<ProcessConfig> {
+        let mut unnamed = 0;
+
+        args.iter()
+            .map(|arg| {
+                let (name, command) = command_with_name(arg.as_ref());
+                let (name, instances) = match name {
+                    Some(name) => split_instances(name),
+                    None => {
+                        unnamed += 1;
+                        (format!("cmd{}", unnamed), None)
+                    }
+                };
+
+                ProcessConfig {
+                    name,
+                    command: command.to_string(),
+                    instances,
+                    ..Default::default()
+                }
+            })
+            .collect()
+    }
+}
+
+/// Signals multip traps and forwards to the processes
+pub const FORWARDED_SIGNALS: [Signal; 8] = [
+    Signal::SIGINT,
+    Signal::SIGTERM,
+    Signal::SIGQUIT,
+    Signal::SIGHUP,
+    Signal::SIGUSR1,
+    Signal::SIGUSR2,
+    Signal::SIGWINCH,
+    Signal::SIGALRM,
+];
+
+/// Forwarded only to the processes with a rule for them. Many servers
+/// stop on SIGWINCH which is sent on every terminal resize.
+const OPT_IN_SIGNALS: [Signal; 2] = [Signal::SIGWINCH, Signal::SIGALRM];
+
+fn is_routable(sig: Signal) -> bool {
+    FORWARDED_SIGNALS.contains(&sig)
+}
+
+pub fn is_opt_in(sig: Signal) -> bool {
+    OPT_IN_SIGNALS.contains(&sig)
+}
+
+/// Split the `name: command` form. The text before the first `:` is not a
+/// name if it has quotes or the `:` is followed by more text without a space
+/// so commands such as `sh -c 'echo a:b'` or `redis-server --bind host:6379`
+/// can be given without a name. Names with spaces are kept so they are
+/// reported as invalid names.
+fn command_with_name(s: &str) -> (Option<&str>, &str) {
+    if let Some(i) = s.find(':') {
+        let name = s[..i].trim();
+        let command = &s[i + 1..];
+
+        let is_name = !name.contains(char::is_whitespace)
+            || command.is_empty()
+            || command.starts_with(char::is_whitespace);
+
+        if is_name && !name.contains(['\'', '"']) {
+            return (Some(name), command.trim());
+        }
+    }
+
+    (None, s.trim())
+}
+
+/// Each instance is a separate process so a typo in the count should not
+/// start thousands of them
+const MAX_INSTANCES: u32 = 1000;
+
+/// Split `name*N`. Invalid counts are left in the name so they are reported
+/// as invalid names.
+fn split_instances(name: &str) -> (String, Option<u32>) {
+    if let Some(i) = name.rfind('*') {
+        if let Ok(instances) = name[i + 1..].parse() {
+            return (name[..i].to_string(), Some(instances));
+        }
+    }
+
+    (name.to_string(), None)
+}
+
+/// Replace the processes having `instances` with a copy for each instance
+pub fn expand_instances(processes: Vec<ProcessConfig>) -> Vec<ProcessConfig> {
+    let mut expanded = Vec::new();
+
+    for process in processes {
+        match process.instances {
+            Some(instances) if instances > 0 => {
+                for instance in 1..=instances {
+                    expanded.push(ProcessConfig {
+                        name: format!("{}.{}", process.name, instance),
+                        instance: Some(instance),
+                        ..process.clone()
+                    });
+                }
+            }
+            _ => expanded.push(process),
+        }
+    }
+
+    expanded
+}
+
+fn is_number(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_digit())
+}
+
+/// `max` or bytes with an optional suffix as accepted by memory.max
+fn is_valid_memory_max(s: &str) -> bool {
+    let s = s.trim();
+    let number = s.trim_end_matches(['k', 'K', 'm', 'M', 'g', 'G', 't', 'T']);
+
+    s == "max" || (is_number(number) && s.len() - number.len() <= 1)
+}
+
+/// `max` or the quota optionally followed by the period as accepted by
+/// cpu.max
+fn is_valid_cpu_max(s: &str) -> bool {
+    let mut words = s.split_whitespace();
+
+    let quota_ok = words
+        .next()
+        .is_some_and(|quota| quota == "max" || is_number(quota));
+    let period_ok = words.next().is_none_or(is_number);
+
+    quota_ok && period_ok && words.next().is_none()
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+}
+
+/// The first name used by more than one process
+pub fn find_duplicate(processes: &[ProcessConfig]) -> Option<&str> {
+    processes
+        .iter()
+        .enumerate()
+        .find(|(i, process)| processes[..*i].iter().any(|p| p.name == process.name))
+        .map(|(_, process)| process.name.as_str())
+}
+
+pub fn load(path: &str) -> Result<Config, String> {
+    let content =
+        fs::read_to_string(path).map_err(|err| format!("Failed to read {}: {}", path, err))?;
+
+    toml::from_str(&content).map_err(|err| format!("Invalid config {}: {}", path, err))
+}
+
+#[test]
+fn parses_signal_names() {
+    assert_eq!(parse_signal("SIGHUP"), Ok(Signal::SIGHUP));
+    assert_eq!(parse_signal("hup"), Ok(Signal::SIGHUP));
+    assert_eq!(parse_signal("USR1"), Ok(Signal::SIGUSR1));
+    assert!(parse_signal("NOPE").is_err());
+}
+
+#[test]
+fn routes_signals() {
+    let mut config = ProcessConfig::from_cli(&["nginx: nginx"]).remove(0);
+    config
+        .signals
+        .insert(String::from("TERM"), String::from("QUIT"));
+    config
+        .signals
+        .insert(String::from("SIGUSR2"), String::from("ignore"));
+
+    assert!(config.validate().is_ok());
+    assert_eq!(config.route_signal(Signal::SIGTERM), Some(Signal::SIGQUIT));
+    assert_eq!(config.route_signal(Signal::SIGUSR2), None);
+    assert_eq!(config.route_signal(Signal::SIGHUP), Some(Signal::SIGHUP));
+    assert_eq!(config.route_signal(Signal::SIGKILL), Some(Signal::SIGKILL));
+    assert_eq!(config.route_signal(Signal::SIGWINCH), None);
+
+    config
+        .signals
+        .insert(String::from("WINCH"), String::from("WINCH"));
+    assert_eq!(
+        config.route_signal(Signal::SIGWINCH),
+        Some(Signal::SIGWINCH)
+    );
+
+    assert_eq!(config.shutdown_signal(Signal::SIGTERM), Signal::SIGQUIT);
+    config.stop_signal = Some(String::from("INT"));
+    assert_eq!(config.shutdown_signal(Signal::SIGTERM), Signal::SIGINT);
+
+    config
+        .signals
+        .insert(String::from("KILL"), String::from("ignore"));
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn parses_cli_names() {
+    let processes = ProcessConfig::from_cli(&[
+        "web: nginx -g 'daemon off;'",
+        "sleep 10",
+        "sh -c 'echo a:b'",
+        " worker :  ./worker ",
+        "redis-server --bind 0.0.0.0:6379",
+    ]);
+    let names: Vec<&str> = processes.iter().map(|p| p.name.as_str()).collect();
+    let commands: Vec<&str> = processes.iter().map(|p| p.command.as_str()).collect();
+
+    assert_eq!(names, vec!["web", "cmd1", "cmd2", "worker", "cmd3"]);
+    assert_eq!(
+        commands,
+        vec![
+            "nginx -g 'daemon off;'",
+            "sleep 10",
+            "sh -c 'echo a:b'",
+            "./worker",
+            "redis-server --bind 0.0.0.0:6379"
+        ]
+    );
+}
+
+#[test]
+fn validates_names() {
+    let validate = |arg: &str| ProcessConfig::from_cli(&[arg]).remove(0).validate();
+
+    assert!(validate("web-1.a_b: nginx").is_ok());
+    assert!(validate(": nginx").is_err());
+    assert!(validate("web@1: nginx").is_err());
+    assert!(validate("web:").is_err());
+    assert!(validate("my web: nginx").is_err());
+
+    let processes = ProcessConfig::from_cli(&["a: true", "b: true", "a: false"]);
+    assert_eq!(find_duplicate(&processes), Some("a"));
+    assert_eq!(find_duplicate(&processes[..2]), None);
+}
+
+#[test]
+fn rejects_zero_line_limits() {
+    let mut config = ProcessConfig::from_cli(&["web: nginx"]).remove(0);
+    config.max_line_length = Some(0);
+    assert!(config.validate().is_err());
+
+    config.max_line_length = Some(1);
+    config.line_memory_limit = Some(0);
+    assert!(config.validate().is_err());
+
+    config.line_memory_limit = Some(1);
+    assert!(config.validate().is_ok());
+}
+
+#[test]
+fn validates_cgroup_limits() {
+    assert!(is_valid_memory_max("512M"));
+    assert!(is_valid_memory_max("1073741824"));
+    assert!(is_valid_memory_max("max"));
+    assert!(!is_valid_memory_max("512MB"));
+    assert!(!is_valid_memory_max("M"));
+
+    assert!(is_valid_cpu_max("50000 100000"));
+    assert!(is_valid_cpu_max("max 100000"));
+    assert!(is_valid_cpu_max("50000"));
+    assert!(!is_valid_cpu_max("half"));
+    assert!(!is_valid_cpu_max("50000 100000 1"));
+    assert!(!is_valid_cpu_max(""));
+}
+
+#[test]
+fn expands_instances() {
+    let mut processes = ProcessConfig::from_cli(&["worker*3: ./worker", "web: nginx"]);
+    processes[0].base_port = Some(8000);
+    assert!(processes[0].validate().is_ok());
+
+    let processes = expand_instances(processes);
+    let names: Vec<&str> = processes.iter().map(|p| p.name.as_str()).collect();
+
+    assert_eq!(names, vec!["worker.1", "worker.2", "worker.3", "web"]);
+    assert_eq!(processes[2].instance, Some(3));
+    assert_eq!(processes[2].port(), Some(8002));
+    assert_eq!(processes[3].port(), None);
+
+    let zero = ProcessConfig::from_cli(&["worker*0: ./worker"]).remove(0);
+    assert!(zero.validate().is_err());
+
+    let mut many = ProcessConfig::from_cli(&["worker*4294967295: ./worker"]).remove(0);
+    assert!(many.validate().is_err());
+    many.base_port = Some(8000);
+    assert!(many.validate().is_err());
+
+    let mut high = ProcessConfig::from_cli(&["worker*2: ./worker"]).remove(0);
+    high.base_port = Some(u16::MAX);
+    assert!(high.validate().is_err());
+}
+
+#[test]
+fn rejects_line_options_with_passthrough() {
+    let mut config = ProcessConfig::from_cli(&["app: ./app"]).remove(0);
+    config.passthrough = Some(Passthrough::Copy);
+    assert!(config.validate().is_ok());
+
+    config.redact.push(RedactConfig {
+        pattern: String::from("secret"),
+        replace: default_redact_replace(),
+    });
+    assert_eq!(
+        config.validate(),
+        Err(String::from(
+            "Invalid redact for [app]: the output of passthrough processes is not read"
+        ))
+    );
+
+    config.redact.clear();
+    config.invalid_utf8 = InvalidUtf8::Raw;
+    assert!(config.validate().is_err());
+}
@@ -0,0 +1,323 @@
+use nix::sys::signal::Signal;
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use crate::config::parse_signal;
+use crate::{log, Channel, Message};
+
+pub const SOCKET_ENV: &str = "MULTIP_CONTROL_SOCKET";
+
+/// Messages waiting to be written to a client. A `logs --follow` client
+/// falling this far behind is disconnected.
+const REPLY_BUFFER_MESSAGES: usize = 1000;
+
+/// Limit for the command line sent by a client
+const MAX_REQUEST_BYTES: u64 = 8192;
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub enum Request {
+    Status,
+    Start(String),
+    Stop(String),
+    Restart(String),
+    Signal(String, Signal),
+    /// Recent output of the given processes or all of them when empty
+    Logs {
+        names: Vec<String>,
+        follow: bool,
+    },
+}
+
+const USAGE: &str = "usage: multip ctl [--socket PATH] <command>
+
+commands:
+    status                  list processes
+    start <name>            start a stopped process
+    stop <name>             stop a process without stopping the others
+    restart <name>          stop and start a process
+    signal <name> <signal>  send a signal to a process
+    logs [--follow] [name...]
+                            show recent output and optionally keep
+                            streaming new lines";
+
+fn expect_name<'a>(words: &[&'a str], command: &str) -> Result<&'a str, String> {
+    match words {
+        [name] => Ok(name),
+        _ => Err(format!("{} requires a process name", command)),
+    }
+}
+
+impl Request {
+    pub fn parse(line: &str) -> Result<Request, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+
+        match words.split_first() {
+            Some((&"status", [])) => Ok(Request::Status),
+            Some((&"start", rest)) => Ok(Request::Start(expect_name(rest, "start")?.to_string())),
+            Some((&"stop", rest)) => Ok(Request::Stop(expect_name(rest, "stop")?.to_string())),
+            Some((&"restart", rest)) => {
+                Ok(Request::Restart(expect_name(rest, "restart")?.to_string()))
+            }
+            Some((&"signal", [name, signal])) => {
+                Ok(Request::Signal(name.to_string(), parse_signal(signal)?))
+            }
+            Some((&"signal", _)) => {
+                Err(String::from("signal requires a process name and a signal"))
+            }
+            Some((&"logs", rest)) => {
+                let follow = rest.iter().any(|&w| w == "--follow" || w == "-f");
+                let names = rest
+                    .iter()
+                    .filter(|&&w| w != "--follow" && w != "-f")
+                    .map(|w| w.to_string())
+                    .collect();
+
+                Ok(Request::Logs { names, follow })
+            }
+            _ => Err(format!("unknown command: {}", line.trim())),
+        }
+    }
+}
+
+/// Sends the response to the client. The first line is `ok` or
+/// `error: <message>` and the rest is the command output. The connection is
+/// closed when the reply is dropped.
+pub struct Reply {
+    tx: mpsc::SyncSender<String>,
+}
+
+impl Reply {
+    pub fn send(self, res: Result<String, String>) {
+        let msg = match res {
+            Ok(body) => format!("ok\n{}", body),
+            Err(err) => format!("error: {}\n", err),
+        };
+
+        // The client may have gone away already
+        self.tx.send(msg).unwrap_or(());
+    }
+
+    /// Send the body but keep the connection open for more output
+    pub fn subscribe(self, body: String) -> Subscriber {
+        let subscriber = Subscriber { tx: self.tx };
+        subscriber.send(format!("ok\n{}", body));
+        subscriber
+    }
+}
+
+/// Connection receiving output until it is closed by either side
+pub struct Subscriber {
+    tx: mpsc::SyncSender<String>,
+}
+
+impl Subscriber {
+    /// Returns false when the client has gone away or is not reading fast
+    /// enough. The connection is closed once the subscriber is dropped.
+    pub fn send(&self, msg: String) -> bool {
+        match self.tx.try_send(msg) {
+            Ok(()) => true,
+            Err(mpsc::TrySendError::Full(_)) => {
+                log!("Closing a logs connection that is not keeping up");
+                false
+            }
+            Err(mpsc::TrySendError::Disconnected(_)) => false,
+        }
+    }
+}
+
+fn handle_connection(stream: UnixStream, tx: Channel) {
+    if let Err(err) = stream.set_read_timeout(Some(READ_TIMEOUT)) {
+        log!("Failed to set control socket read timeout: {}", err);
+        return;
+    }
+
+    let mut line = String::new();
+    let mut reader = BufReader::new((&stream).take(MAX_REQUEST_BYTES));
+    if let Err(err) = reader.read_line(&mut line) {
+        log!("Failed to read control command: {}", err);
+        return;
+    }
+
+    let (reply_tx, reply_rx) = mpsc::sync_channel(REPLY_BUFFER_MESSAGES);
+    let reply = Reply { tx: reply_tx };
+
+    match Request::parse(&line) {
+        Ok(request) => {
+            if tx.send(Message::Control(request, reply)).is_err() {
+                return;
+            }
+        }
+        Err(err) => reply.send(Err(err)),
+    }
+
+    let mut stream = stream;
+    for msg in reply_rx {
+        if stream.write_all(msg.as_bytes()).is_err() {
+            break;
+        }
+    }
+}
+
+/// Remove the socket left behind by a previous run. Anything else at the
+/// path is left alone.
+pub fn remove_stale_socket(path: &str) -> Result<(), String> {
+    let meta = match fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        Err(_) => return Ok(()),
+    };
+
+    if !meta.file_type().is_socket() {
+        return Err(format!("Cannot listen on {}: not a socket", path));
+    }
+
+    fs::remove_file(path).map_err(|err| format!("Failed to remove {}: {}", path, err))
+}
+
+/// Listen for the control commands on the unix socket and pass them to the
+/// main loop
+pub fn listen(path: &str, tx: Channel) -> Result<(), String> {
+    remove_stale_socket(path)?;
+
+    let listener =
+        UnixListener::bind(path).map_err(|err| format!("Failed to bind {}: {}", path, err))?;
+
+    log!("Listening for control commands on {}", path);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let tx = Channel::clone(&tx);
+                    thread::spawn(move || handle_connection(stream, tx));
+                }
+                Err(err) => {
+                    log!("Control socket connection failed: {}", err);
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// The `multip ctl` subcommand. Returns the exit code.
+pub fn client(args: &[String]) -> i32 {
+    let mut socket_path = env::var(SOCKET_ENV).ok();
+    let mut words: Vec<&str> = Vec::new();
+    let mut args_iter = args.iter();
+
+    while let Some(arg) = args_iter.next() {
+        if arg == "--socket" {
+            socket_path = args_iter.next().cloned();
+        } else {
+            words.push(arg);
+        }
+    }
+
+    let command = words.join(" ");
+
+    if let Err(err) = Request::parse(&command) {
+        eprintln!("{}\n\n{}", err, USAGE);
+        return 2;
+    }
+
+    let socket_path = match socket_path {
+        Some(path) => path,
+        None => {
+            eprintln!("Set the socket path with {} or --socket", SOCKET_ENV);
+            return 2;
+        }
+    };
+
+    let mut stream = match UnixStream::connect(&socket_path) {
+        Ok(stream) => stream,
+        Err(err) => {
+            eprintln!("Failed to connect to {}: {}", socket_path, err);
+            return 1;
+        }
+    };
+
+    if let Err(err) = writeln!(stream, "{}", command) {
+        eprintln!("Failed to send command: {}", err);
+        return 1;
+    }
+
+    let mut reader = BufReader::new(stream);
+    let mut status = String::new();
+    if let Err(err) = reader.read_line(&mut status) {
+        eprintln!("Failed to read response: {}", err);
+        return 1;
+    }
+
+    if let Some(err) = status.strip_prefix("error: ") {
+        eprint!("{}", err);
+        return 1;
+    }
+
+    if status.trim_end() != "ok" {
+        eprintln!("Unexpected response: {}", status.trim_end());
+        return 1;
+    }
+
+    let mut out = std::io::stdout();
+    let mut buf = [0; 8 * 1024];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => {
+                if out
+                    .write_all(&buf[..len])
+                    .and_then(|_| out.flush())
+                    .is_err()
+                {
+                    break;
+                }
+            }
+            Err(err) => {
+                eprintln!("Failed to read response: {}", err);
+                return 1;
+            }
+        }
+    }
+
+    0
+}
+
+#[test]
+fn parses_requests() {
+    assert!(matches!(Request::parse("status\n"), Ok(Request::Status)));
+    assert!(matches!(Request::parse("stop web"), Ok(Request::Stop(name)) if name == "web"));
+    assert!(matches!(
+        Request::parse("signal web HUP"),
+        Ok(Request::Signal(name, Signal::SIGHUP)) if name == "web"
+    ));
+    assert!(matches!(
+        Request::parse("logs -f web worker"),
+        Ok(Request::Logs { names, follow: true }) if names == vec!["web", "worker"]
+    ));
+    assert!(matches!(
+        Request::parse("logs"),
+        Ok(Request::Logs { names, follow: false }) if names.is_empty()
+    ));
+    assert!(Request::parse("stop").is_err());
+    assert!(Request::parse("signal web NOPE").is_err());
+    assert!(Request::parse("dance").is_err());
+}
+
+#[test]
+fn drops_slow_subscribers() {
+    let (tx, rx) = mpsc::sync_channel(2);
+    let subscriber = Reply { tx }.subscribe(String::new());
+
+    assert!(subscriber.send(String::from("a\n")));
+    assert!(!subscriber.send(String::from("b\n")));
+
+    drop(subscriber);
+    assert_eq!(rx.iter().collect::<Vec<_>>(), vec!["ok\n", "a\n"]);
+}
@@ -0,0 +1,235 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Cron schedule with the minute, hour, day of month, month and day of week
+/// fields and an optional seconds field before them. The times are in UTC.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Schedule {
+    seconds: u64,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// The day of month and the day of week match either one unless one of
+    /// them is `*`
+    any_day: bool,
+    any_weekday: bool,
+}
+
+/// Give up looking for the next time after this many years. Happens with
+/// dates such as February 30th.
+const MAX_YEARS: i64 = 5;
+
+/// Parse a single field such as `*/15`, `1-5` or `0,30` into a bit mask
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut mask = 0;
+
+    for part in field.split(',') {
+        let (range, step) = match part.find('/') {
+            Some(i) => {
+                let step: u32 = part[i + 1..]
+                    .parse()
+                    .map_err(|_| format!("Invalid step in {}", part))?;
+                if step == 0 {
+                    return Err(format!("Invalid step in {}", part));
+                }
+                (&part[..i], step)
+            }
+            None => (part, 1),
+        };
+
+        let parse = |s: &str| -> Result<u32, String> {
+            match s.parse() {
+                Ok(n) if n >= min && n <= max => Ok(n),
+                _ => Err(format!("{} is not between {} and {}", s, min, max)),
+            }
+        };
+
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some(i) = range.find('-') {
+            (parse(&range[..i])?, parse(&range[i + 1..])?)
+        } else if step > 1 {
+            // `5/15` means from 5 to the end
+            (parse(range)?, max)
+        } else {
+            let n = parse(range)?;
+            (n, n)
+        };
+
+        if start > end {
+            return Err(format!("Invalid range {}", range));
+        }
+
+        for n in (start..=end).step_by(step as usize) {
+            mask |= 1 << n;
+        }
+    }
+
+    Ok(mask)
+}
+
+fn has(mask: u64, n: i64) -> bool {
+    mask & (1 << n) != 0
+}
+
+/// Days since 1970-01-01 for the date
+#[cfg(test)]
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_index = (month + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Date of the days since 1970-01-01
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+impl Schedule {
+    pub fn parse(s: &str) -> Result<Schedule, String> {
+        let expanded = match s.trim() {
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            s => s,
+        };
+
+        let mut fields: Vec<&str> = expanded.split_whitespace().collect();
+        let seconds = match fields.len() {
+            5 => 1,
+            6 => parse_field(fields.remove(0), 0, 59)?,
+            _ => return Err(format!("Expected 5 or 6 fields in \"{}\"", s)),
+        };
+
+        let weekdays = parse_field(fields[4], 0, 7)?;
+
+        Ok(Schedule {
+            seconds,
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            // Both 0 and 7 are Sunday
+            weekdays: (weekdays | weekdays >> 7) & 0x7f,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+
+    fn matches_day(&self, days: i64, day: i64) -> bool {
+        // 1970-01-01 was a Thursday
+        let weekday = (days + 4).rem_euclid(7);
+
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (false, true) => has(self.days, day),
+            (true, false) => has(self.weekdays, weekday),
+            (false, false) => has(self.days, day) || has(self.weekdays, weekday),
+        }
+    }
+
+    /// The first matching time after the given time
+    pub fn next_after(&self, time: SystemTime) -> Option<SystemTime> {
+        let secs = time.duration_since(UNIX_EPOCH).ok()?.as_secs() as i64 + 1;
+        let mut days = secs.div_euclid(86400);
+        let mut start = secs.rem_euclid(86400);
+        let last_day = days + MAX_YEARS * 366;
+
+        while days <= last_day {
+            let (_, month, day) = civil_from_days(days);
+
+            if has(self.months, month) && self.matches_day(days, day) {
+                for minute in start / 60..24 * 60 {
+                    if !has(self.hours, minute / 60) || !has(self.minutes, minute % 60) {
+                        continue;
+                    }
+
+                    let first = if minute == start / 60 { start % 60 } else { 0 };
+                    if let Some(second) = (first..60).find(|&second| has(self.seconds, second)) {
+                        let secs = (days * 86400 + minute * 60 + second) as u64;
+                        return Some(UNIX_EPOCH + Duration::from_secs(secs));
+                    }
+                }
+            }
+
+            days += 1;
+            start = 0;
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+fn utc(year: i64, month: i64, day: i64, hour: i64, minute: i64) -> SystemTime {
+    let days = days_from_civil(year, month, day);
+    UNIX_EPOCH + Duration::from_secs((days * 86400 + hour * 3600 + minute * 60) as u64)
+}
+
+#[test]
+fn converts_dates() {
+    assert_eq!(days_from_civil(1970, 1, 1), 0);
+    assert_eq!(days_from_civil(2000, 3, 1), 11017);
+    assert_eq!(civil_from_days(11017), (2000, 3, 1));
+    assert_eq!(civil_from_days(days_from_civil(2024, 2, 29)), (2024, 2, 29));
+}
+
+#[test]
+fn parses_schedules() {
+    assert!(Schedule::parse("*/15 * * * *").is_ok());
+    assert!(Schedule::parse("0 3 * * 1-5").is_ok());
+    assert!(Schedule::parse("@daily").is_ok());
+    assert!(Schedule::parse("*/10 * * * * *").is_ok());
+    assert!(Schedule::parse("* * * *").is_err());
+    assert!(Schedule::parse("60 * * * *").is_err());
+    assert!(Schedule::parse("*/0 * * * *").is_err());
+    assert!(Schedule::parse("5-1 * * * *").is_err());
+}
+
+#[test]
+fn finds_next_run() {
+    let next = |schedule: &str, time| Schedule::parse(schedule).unwrap().next_after(time);
+
+    let now = utc(2024, 2, 28, 23, 59);
+    assert_eq!(next("*/15 * * * *", now), Some(utc(2024, 2, 29, 0, 0)));
+    assert_eq!(next("30 3 * * *", now), Some(utc(2024, 2, 29, 3, 30)));
+    assert_eq!(next("0 0 1 * *", now), Some(utc(2024, 3, 1, 0, 0)));
+    // 2024-03-03 is a Sunday
+    assert_eq!(next("0 12 * * 7", now), Some(utc(2024, 3, 3, 12, 0)));
+    // Either the day of month or the day of week
+    assert_eq!(next("0 12 15 * 0", now), Some(utc(2024, 3, 3, 12, 0)));
+    assert_eq!(next("0 0 30 2 *", now), None);
+
+    // Always after the given time
+    let now = utc(2024, 1, 1, 10, 15);
+    assert_eq!(next("15 10 * * *", now), Some(utc(2024, 1, 2, 10, 15)));
+
+    // With seconds
+    let in_secs = |secs| now + Duration::from_secs(secs);
+    assert_eq!(next("* * * * * *", now), Some(in_secs(1)));
+    assert_eq!(next("*/20 * * * * *", in_secs(1)), Some(in_secs(20)));
+    assert_eq!(next("30 16 10 * * *", now), Some(in_secs(60 + 30)));
+}
@@ -0,0 +1,204 @@
+use nix::errno::Errno;
+use nix::sys::epoll::{epoll_create1, epoll_ctl, epoll_wait};
+use nix::sys::epoll::{EpollCreateFlags, EpollEvent, EpollFlags, EpollOp};
+use nix::sys::eventfd::{eventfd, EfdFlags};
+use nix::sys::signal::{SigSet, Signal};
+use nix::sys::signalfd::{SfdFlags, SignalFd};
+use nix::unistd::{read, write};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SendError, TryRecvError};
+use std::time::Instant;
+
+use crate::{log, Message};
+
+/// Sends messages to the main loop from the other threads
+#[derive(Clone)]
+pub struct Channel {
+    tx: mpsc::Sender<Message>,
+    wake_fd: RawFd,
+}
+
+impl Channel {
+    pub fn send(&self, msg: Message) -> Result<(), SendError<Message>> {
+        self.tx.send(msg)?;
+
+        // Adds to the eventfd counter. Fails only when the counter is about
+        // to overflow which still leaves it readable.
+        write(self.wake_fd, &1u64.to_ne_bytes())
+            .map(drop)
+            .unwrap_or(());
+        Ok(())
+    }
+}
+
+/// Waits for the signals, the messages from the other threads and the file
+/// descriptors of the children in a single epoll set
+pub struct EventLoop {
+    epoll_fd: RawFd,
+    signal_fd: SignalFd,
+    wake_fd: RawFd,
+    rx: Receiver<Message>,
+    /// Events read but not handled yet
+    pending: VecDeque<Message>,
+    /// Handled after the events found by the next poll
+    deferred: VecDeque<Message>,
+}
+
+impl EventLoop {
+    /// The signals are blocked so they are only read from the signalfd. Must
+    /// be called before any threads are started since they would inherit the
+    /// signal mask of the thread starting them.
+    pub fn new(signals: &[Signal]) -> Result<(EventLoop, Channel), String> {
+        let mut mask = SigSet::empty();
+        for sig in signals.iter() {
+            mask.add(*sig);
+        }
+
+        mask.thread_block()
+            .map_err(|err| format!("Failed to block signals: {}", err))?;
+
+        let signal_fd = SignalFd::with_flags(&mask, SfdFlags::SFD_NONBLOCK | SfdFlags::SFD_CLOEXEC)
+            .map_err(|err| format!("Failed to create signalfd: {}", err))?;
+        let wake_fd = eventfd(0, EfdFlags::EFD_NONBLOCK | EfdFlags::EFD_CLOEXEC)
+            .map_err(|err| format!("Failed to create eventfd: {}", err))?;
+        let epoll_fd = epoll_create1(EpollCreateFlags::EPOLL_CLOEXEC)
+            .map_err(|err| format!("Failed to create epoll: {}", err))?;
+
+        let (tx, rx) = mpsc::channel();
+        let events = EventLoop {
+            epoll_fd,
+            signal_fd,
+            wake_fd,
+            rx,
+            pending: VecDeque::new(),
+            deferred: VecDeque::new(),
+        };
+
+        events.add(events.signal_fd.as_raw_fd());
+        events.add(wake_fd);
+
+        Ok((events, Channel { tx, wake_fd }))
+    }
+
+    /// Wake up with `Message::Readable` while the file descriptor is readable
+    pub fn add(&self, fd: RawFd) {
+        let mut event = EpollEvent::new(EpollFlags::EPOLLIN, fd as u64);
+
+        if let Err(err) = epoll_ctl(self.epoll_fd, EpollOp::EpollCtlAdd, fd, &mut event) {
+            log!("Failed to add fd {} to epoll: {}", fd, err);
+        }
+    }
+
+    pub fn remove(&self, fd: RawFd) {
+        epoll_ctl(self.epoll_fd, EpollOp::EpollCtlDel, fd, None).unwrap_or(());
+    }
+
+    /// Handle the message after the events received already and the ones
+    /// ready by then. A stream with more to read does not keep the signals
+    /// and the other streams waiting.
+    pub fn defer(&mut self, msg: Message) {
+        self.deferred.push_back(msg);
+    }
+
+    /// Queue the deferred messages behind the polled ones. A readable file
+    /// descriptor reported by the poll already is not read twice.
+    fn queue_deferred(&mut self) {
+        while let Some(msg) = self.deferred.pop_front() {
+            let polled = match &msg {
+                Message::Readable(fd) => self
+                    .pending
+                    .iter()
+                    .any(|queued| matches!(queued, Message::Readable(q) if q == fd)),
+                _ => false,
+            };
+
+            if !polled {
+                self.pending.push_back(msg);
+            }
+        }
+    }
+
+    /// Queue the received signals in the order they arrived
+    fn read_signals(&mut self) {
+        loop {
+            match self.signal_fd.read_signal() {
+                Ok(Some(info)) => match Signal::try_from(info.ssi_signo as i32) {
+                    Ok(sig) => self.pending.push_back(Message::ParentSignal(sig)),
+                    Err(_) => {
+                        log!("Signal parsing failed");
+                    }
+                },
+                Ok(None) => return,
+                Err(err) => {
+                    log!("Failed to read signals: {}", err);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Queue the messages sent with the channel
+    fn read_messages(&mut self) -> Result<(), RecvTimeoutError> {
+        let mut counter = [0u8; 8];
+        read(self.wake_fd, &mut counter).map(drop).unwrap_or(());
+
+        loop {
+            match self.rx.try_recv() {
+                Ok(msg) => self.pending.push_back(msg),
+                Err(TryRecvError::Empty) => return Ok(()),
+                Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+            }
+        }
+    }
+
+    /// Wait for the next event until the deadline
+    pub fn next(&mut self, deadline: Option<Instant>) -> Result<Message, RecvTimeoutError> {
+        let mut events = [EpollEvent::empty(); 64];
+
+        loop {
+            if let Some(msg) = self.pending.pop_front() {
+                return Ok(msg);
+            }
+
+            let timeout_ms = match deadline {
+                // Only look for the events ready already
+                _ if !self.deferred.is_empty() => 0,
+                // Rounded up so the deadline has passed on wake up
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left.as_nanos() == 0 {
+                        return Err(RecvTimeoutError::Timeout);
+                    }
+                    (left.as_nanos() as isize + 999_999) / 1_000_000
+                }
+                None => -1,
+            };
+
+            let count = match epoll_wait(self.epoll_fd, &mut events, timeout_ms) {
+                Ok(0) if self.deferred.is_empty() => return Err(RecvTimeoutError::Timeout),
+                Ok(count) => count,
+                Err(nix::Error::Sys(Errno::EINTR)) => continue,
+                Err(err) => {
+                    log!("epoll_wait failed: {}", err);
+                    return Err(RecvTimeoutError::Disconnected);
+                }
+            };
+
+            for event in events[..count].iter() {
+                let fd = event.data() as RawFd;
+
+                if fd == self.signal_fd.as_raw_fd() {
+                    self.read_signals();
+                } else if fd == self.wake_fd {
+                    self.read_messages()?;
+                } else {
+                    self.pending.push_back(Message::Readable(fd));
+                }
+            }
+
+            self.queue_deferred();
+        }
+    }
+}
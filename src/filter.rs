@@ -0,0 +1,198 @@
+use regex::Regex;
+use std::borrow::Cow;
+
+use crate::config::ProcessConfig;
+use crate::line_reader::Line;
+
+/// Include/exclude rules and redactions applied to the child output before
+/// it is printed
+pub struct OutputFilter {
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+    redact: Vec<(Regex, String)>,
+}
+
+fn compile(name: &str, pattern: &str) -> Result<Regex, String> {
+    Regex::new(pattern).map_err(|err| format!("Invalid pattern for [{}]: {}", name, err))
+}
+
+fn split_line_ending(s: &str) -> (&str, &str) {
+    let content = s.trim_end_matches(['\n', '\r']);
+    (content, &s[content.len()..])
+}
+
+impl OutputFilter {
+    pub fn new(config: &ProcessConfig) -> Result<OutputFilter, String> {
+        let name = &config.name;
+
+        let include = config
+            .include
+            .iter()
+            .map(|pattern| compile(name, pattern))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let exclude = config
+            .exclude
+            .iter()
+            .map(|pattern| compile(name, pattern))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let redact = config
+            .redact
+            .iter()
+            .map(|r| Ok((compile(name, &r.pattern)?, r.replace.clone())))
+            .collect::<Result<Vec<_>, String>>()?;
+
+        Ok(OutputFilter {
+            include,
+            exclude,
+            redact,
+        })
+    }
+
+    pub fn has_redactions(&self) -> bool {
+        !self.redact.is_empty()
+    }
+
+    /// Returns None when the line should be dropped
+    pub fn apply<'a>(&self, line: &'a str) -> Option<Cow<'a, str>> {
+        if self.include.is_empty() && self.exclude.is_empty() && self.redact.is_empty() {
+            return Some(Cow::Borrowed(line));
+        }
+
+        // Match without the line ending so `$` works as expected and
+        // redactions cannot eat the newline
+        let (content, ending) = split_line_ending(line);
+
+        if !self.include.is_empty() && !self.include.iter().any(|re| re.is_match(content)) {
+            return None;
+        }
+
+        if self.exclude.iter().any(|re| re.is_match(content)) {
+            return None;
+        }
+
+        if self.redact.is_empty() {
+            return Some(Cow::Borrowed(line));
+        }
+
+        let mut redacted = content.to_string();
+        for (re, replace) in self.redact.iter() {
+            redacted = re.replace_all(&redacted, replace.as_str()).into_owned();
+        }
+        redacted.push_str(ending);
+
+        Some(Cow::Owned(redacted))
+    }
+
+    /// Long lines are filtered chunk by chunk as they are split by the
+    /// SafeLineReader unless there are redactions. The lines of processes
+    /// with redactions are read whole and split after filtering.
+    pub fn apply_line(&self, line: Line) -> Option<Line> {
+        match line {
+            Line::FullLine(s) => self.apply(&s).map(|s| Line::FullLine(s.into_owned())),
+            Line::PartialLine(s) => self.apply(&s).map(|s| Line::PartialLine(s.into_owned())),
+            // Match against the lossy version but keep the bytes as is unless
+            // something was redacted
+            Line::Raw(bytes) => {
+                let lossy = String::from_utf8_lossy(&bytes).into_owned();
+                match self.apply(&lossy)? {
+                    Cow::Owned(redacted) if redacted != lossy => Some(Line::FullLine(redacted)),
+                    _ => Some(Line::Raw(bytes)),
+                }
+            }
+            // EOF must always get through since it ends the output stream
+            Line::EOF(s) => Some(Line::EOF(
+                self.apply(&s).map(Cow::into_owned).unwrap_or_default(),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+fn filter_from(include: &[&str], exclude: &[&str], redact: &[(&str, &str)]) -> OutputFilter {
+    use crate::config::RedactConfig;
+
+    OutputFilter::new(&ProcessConfig {
+        name: String::from("test"),
+        include: include.iter().map(|s| s.to_string()).collect(),
+        exclude: exclude.iter().map(|s| s.to_string()).collect(),
+        redact: redact
+            .iter()
+            .map(|(pattern, replace)| RedactConfig {
+                pattern: pattern.to_string(),
+                replace: replace.to_string(),
+            })
+            .collect(),
+        ..Default::default()
+    })
+    .unwrap()
+}
+
+#[test]
+fn passes_everything_without_rules() {
+    let filter = filter_from(&[], &[], &[]);
+    assert_eq!(filter.apply("hello\n").unwrap(), "hello\n");
+}
+
+#[test]
+fn excludes_matching_lines() {
+    let filter = filter_from(&[], &["GET /healthz"], &[]);
+    assert!(filter.apply("GET /healthz 200\n").is_none());
+    assert_eq!(filter.apply("GET / 200\n").unwrap(), "GET / 200\n");
+}
+
+#[test]
+fn includes_only_matching_lines() {
+    let filter = filter_from(&["^ERROR", "^WARN"], &[], &[]);
+    assert!(filter.apply("INFO ok\n").is_none());
+    assert_eq!(filter.apply("WARN hmm\n").unwrap(), "WARN hmm\n");
+}
+
+#[test]
+fn exclude_wins_over_include() {
+    let filter = filter_from(&["^ERROR"], &["ignore me"], &[]);
+    assert!(filter.apply("ERROR ignore me\n").is_none());
+}
+
+#[test]
+fn redacts_secrets() {
+    let filter = filter_from(
+        &[],
+        &[],
+        &[
+            (r"token=\w+", "token=***"),
+            (r"password=\S+$", "password=***"),
+        ],
+    );
+    assert_eq!(
+        filter.apply("url?token=abc123 password=hunter2\n").unwrap(),
+        "url?token=*** password=***\n"
+    );
+}
+
+#[test]
+fn redact_supports_capture_groups() {
+    let filter = filter_from(&[], &[], &[(r"(user)=\w+", "$1=<hidden>")]);
+    assert_eq!(filter.apply("user=bob\n").unwrap(), "user=<hidden>\n");
+}
+
+#[test]
+fn eof_is_never_dropped() {
+    let filter = filter_from(&[], &["secret"], &[]);
+    match filter.apply_line(Line::EOF(String::from("secret"))) {
+        Some(Line::EOF(s)) => assert_eq!(s, ""),
+        _ => panic!("Expected EOF"),
+    }
+}
+
+#[test]
+fn invalid_pattern_is_an_error() {
+    let res = OutputFilter::new(&ProcessConfig {
+        name: String::from("web"),
+        exclude: vec![String::from("(")],
+        ..Default::default()
+    });
+
+    assert!(res.is_err());
+}
@@ -0,0 +1,66 @@
+use serde_json::{json, Map, Value};
+
+use crate::http::Response;
+use crate::MultipChild;
+
+fn process_details(children: &[&MultipChild]) -> Value {
+    let mut processes = Map::new();
+
+    for child in children {
+        let pid = if child.is_process_alive() {
+            Value::from(child.cmd.id())
+        } else {
+            Value::Null
+        };
+
+        processes.insert(
+            child.name().to_string(),
+            json!({
+                "state": child.state(),
+                "pid": pid,
+                "ready": child.is_ready(),
+                "failed": child.has_failed(),
+                "restarts": child.restarts,
+                "last_exit_code": child.last_exit_code,
+            }),
+        );
+    }
+
+    Value::Object(processes)
+}
+
+fn respond(ok: bool, status: &str, children: &[&MultipChild]) -> Response {
+    let body = json!({
+        "status": status,
+        "processes": process_details(children),
+    });
+
+    Response::new(
+        if ok { 200 } else { 503 },
+        "application/json",
+        body.to_string() + "\n",
+    )
+}
+
+fn current(children: &[MultipChild]) -> Vec<&MultipChild> {
+    children.iter().filter(|child| !child.removed).collect()
+}
+
+/// Healthy while no process has exited on its own
+pub fn healthz(children: &[MultipChild]) -> Response {
+    let children = current(children);
+    let ok = !children.iter().any(|child| child.has_failed());
+
+    respond(ok, if ok { "ok" } else { "failed" }, &children)
+}
+
+/// Ready when every process is running and ready. The jobs are ready
+/// whether they are running or not.
+pub fn readyz(children: &[MultipChild]) -> Response {
+    let children = current(children);
+    let ok = children
+        .iter()
+        .all(|child| child.is_ready() || child.config.is_job());
+
+    respond(ok, if ok { "ready" } else { "not ready" }, &children)
+}
@@ -0,0 +1,230 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use crate::{control, log, Channel, Message};
+
+pub const LISTEN_ENV: &str = "MULTIP_HTTP_LISTEN";
+
+/// Limit for the request line and the headers together
+const MAX_REQUEST_BYTES: u64 = 8192;
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A TCP or unix socket connection
+trait Connection: Read + Write {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+impl Connection for UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Address {
+    Tcp(String),
+    Unix(String),
+}
+
+/// `unix:/path` or an absolute path listens on a unix socket, a bare port on
+/// localhost and anything else is used as `host:port`
+pub fn parse_address(s: &str) -> Address {
+    if let Some(path) = s.strip_prefix("unix:") {
+        Address::Unix(path.to_string())
+    } else if s.starts_with('/') {
+        Address::Unix(s.to_string())
+    } else if s.parse::<u16>().is_ok() {
+        Address::Tcp(format!("127.0.0.1:{}", s))
+    } else {
+        Address::Tcp(s.to_string())
+    }
+}
+
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &'static str, body: String) -> Response {
+        Response {
+            status,
+            content_type,
+            body,
+        }
+    }
+
+    pub fn not_found() -> Response {
+        Response::new(404, "text/plain", String::from("Not found\n"))
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            503 => "Service Unavailable",
+            _ => "",
+        }
+    }
+}
+
+/// Sends the response for a request handled by the main loop
+pub struct Reply {
+    tx: mpsc::Sender<Response>,
+}
+
+impl Reply {
+    pub fn send(self, response: Response) {
+        // The client may have gone away already
+        self.tx.send(response).unwrap_or(());
+    }
+}
+
+/// Method and path of the request without the query string
+fn parse_request_line(line: &str) -> Option<(&str, &str)> {
+    let mut words = line.split_whitespace();
+    let method = words.next()?;
+    let target = words.next()?;
+    let path = target.split('?').next()?;
+
+    Some((method, path))
+}
+
+fn handle_connection<S: Connection>(mut stream: S, tx: Channel) {
+    if let Err(err) = stream.set_read_timeout(Some(READ_TIMEOUT)) {
+        log!("Failed to set HTTP read timeout: {}", err);
+        return;
+    }
+
+    let mut reader = BufReader::new((&mut stream).take(MAX_REQUEST_BYTES));
+    let mut request_line = String::new();
+    if let Err(err) = reader.read_line(&mut request_line) {
+        log!("Failed to read HTTP request: {}", err);
+        return;
+    }
+
+    // Skip the headers
+    let mut header = String::new();
+    while reader.read_line(&mut header).is_ok_and(|len| len > 0) {
+        if header.trim_end().is_empty() {
+            break;
+        }
+        header.clear();
+    }
+
+    let response = match parse_request_line(&request_line) {
+        Some(("GET", path)) => {
+            let (reply_tx, reply_rx) = mpsc::channel();
+            let reply = Reply { tx: reply_tx };
+
+            if tx.send(Message::Http(path.to_string(), reply)).is_err() {
+                return;
+            }
+
+            match reply_rx.recv() {
+                Ok(response) => response,
+                Err(_) => return,
+            }
+        }
+        Some(_) => Response::new(405, "text/plain", String::from("Method not allowed\n")),
+        None => return,
+    };
+
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.reason(),
+        response.content_type,
+        response.body.len()
+    );
+
+    stream
+        .write_all(head.as_bytes())
+        .and_then(|_| stream.write_all(response.body.as_bytes()))
+        .unwrap_or(());
+}
+
+fn accept<S>(incoming: impl Iterator<Item = io::Result<S>>, tx: Channel)
+where
+    S: Connection + Send + 'static,
+{
+    for stream in incoming {
+        match stream {
+            Ok(stream) => {
+                let tx = Channel::clone(&tx);
+                thread::spawn(move || handle_connection(stream, tx));
+            }
+            Err(err) => {
+                log!("HTTP connection failed: {}", err);
+            }
+        }
+    }
+}
+
+/// Serve HTTP on the address. The requests are answered by the main loop.
+pub fn listen(address: &Address, tx: Channel) -> Result<(), String> {
+    match address {
+        Address::Tcp(addr) => {
+            let listener = TcpListener::bind(addr)
+                .map_err(|err| format!("Failed to bind {}: {}", addr, err))?;
+
+            log!("Listening for HTTP on {}", addr);
+
+            thread::spawn(move || accept(listener.incoming(), tx));
+        }
+
+        Address::Unix(path) => {
+            control::remove_stale_socket(path)?;
+
+            let listener = UnixListener::bind(path)
+                .map_err(|err| format!("Failed to bind {}: {}", path, err))?;
+
+            log!("Listening for HTTP on {}", path);
+
+            thread::spawn(move || accept(listener.incoming(), tx));
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn parses_addresses() {
+    assert_eq!(
+        parse_address("9100"),
+        Address::Tcp(String::from("127.0.0.1:9100"))
+    );
+    assert_eq!(
+        parse_address("0.0.0.0:9100"),
+        Address::Tcp(String::from("0.0.0.0:9100"))
+    );
+    assert_eq!(
+        parse_address("unix:/run/multip.http"),
+        Address::Unix(String::from("/run/multip.http"))
+    );
+    assert_eq!(
+        parse_address("/run/multip.http"),
+        Address::Unix(String::from("/run/multip.http"))
+    );
+}
+
+#[test]
+fn parses_request_lines() {
+    assert_eq!(
+        parse_request_line("GET /metrics?x=1 HTTP/1.1\r\n"),
+        Some(("GET", "/metrics"))
+    );
+    assert_eq!(parse_request_line("\r\n"), None);
+}
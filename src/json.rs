@@ -0,0 +1,116 @@
+use serde_json::{Map, Value};
+
+use crate::line_reader;
+use crate::Line;
+
+fn parse_object(s: &str) -> Option<Map<String, Value>> {
+    let s = s.trim();
+
+    // Quick check before doing the full parse for every line
+    if !s.starts_with('{') {
+        return None;
+    }
+
+    match serde_json::from_str(s) {
+        Ok(Value::Object(fields)) => Some(fields),
+        _ => None,
+    }
+}
+
+fn insert_content(record: &mut Map<String, Value>, s: &str) {
+    match parse_object(s) {
+        Some(fields) => {
+            for (key, value) in fields {
+                if !record.contains_key(&key) {
+                    record.insert(key, value);
+                }
+            }
+        }
+        None => {
+            record.insert(String::from("message"), Value::from(s.trim_end()));
+        }
+    }
+}
+
+/// Format the line as a JSON record. Lines that are JSON objects themselves
+/// get their fields merged into the record. The `process`, `pid` and `stream`
+/// fields set by multip take precedence over the ones from the child.
+pub fn format_line(line: &Line) -> Option<String> {
+    let mut record = Map::new();
+    record.insert(String::from("process"), Value::from(line.name.as_str()));
+    record.insert(String::from("pid"), Value::from(line.pid));
+    record.insert(String::from("stream"), Value::from(line.stream.as_str()));
+
+    match &line.line {
+        Err(err) => {
+            record.insert(String::from("error"), Value::from(err.to_string()));
+        }
+        Ok(line_reader::Line::PartialLine(s)) => {
+            record.insert(String::from("message"), Value::from(s.trim_end()));
+            record.insert(String::from("partial"), Value::from(true));
+        }
+        Ok(line_reader::Line::Raw(bytes)) => {
+            let s = line_reader::escape_invalid_utf8(bytes);
+            record.insert(String::from("message"), Value::from(s.trim_end()));
+        }
+        Ok(line_reader::Line::EOF(s)) if s.trim_end().is_empty() => return None,
+        Ok(line_reader::Line::EOF(s)) | Ok(line_reader::Line::FullLine(s)) => {
+            insert_content(&mut record, s);
+        }
+    }
+
+    Some(Value::Object(record).to_string())
+}
+
+#[cfg(test)]
+fn test_line(s: &str) -> Line {
+    Line {
+        name: String::from("web"),
+        pid: 42,
+        stream: crate::Stream::Stdout,
+        line: Ok(line_reader::Line::FullLine(s.to_string())),
+    }
+}
+
+#[test]
+fn wraps_plain_lines() {
+    assert_eq!(
+        format_line(&test_line("hello\n")).unwrap(),
+        r#"{"process":"web","pid":42,"stream":"stdout","message":"hello"}"#
+    );
+}
+
+#[test]
+fn merges_json_objects() {
+    assert_eq!(
+        format_line(&test_line("{\"level\":\"info\",\"msg\":\"hi\"}\n")).unwrap(),
+        r#"{"process":"web","pid":42,"stream":"stdout","level":"info","msg":"hi"}"#
+    );
+}
+
+#[test]
+fn multip_fields_take_precedence() {
+    assert_eq!(
+        format_line(&test_line("{\"pid\":1,\"msg\":\"hi\"}\n")).unwrap(),
+        r#"{"process":"web","pid":42,"stream":"stdout","msg":"hi"}"#
+    );
+}
+
+#[test]
+fn non_object_json_is_a_message() {
+    assert_eq!(
+        format_line(&test_line("[1,2]\n")).unwrap(),
+        r#"{"process":"web","pid":42,"stream":"stdout","message":"[1,2]"}"#
+    );
+    assert_eq!(
+        format_line(&test_line("{broken\n")).unwrap(),
+        r#"{"process":"web","pid":42,"stream":"stdout","message":"{broken"}"#
+    );
+}
+
+#[test]
+fn empty_eof_is_skipped() {
+    let mut line = test_line("");
+    line.line = Ok(line_reader::Line::EOF(String::new()));
+    assert!(format_line(&line).is_none());
+}
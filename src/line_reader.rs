@@ -1,97 +1,129 @@
-use core::str::from_utf8;
-use std::cmp;
-use std::convert::TryFrom;
 use std::fmt;
 use std::io::{BufRead, BufReader, Read};
 use std::io::{Error, ErrorKind};
+use std::str::from_utf8;
 
-struct Guard<'a> {
-    buf: &'a mut Vec<u8>,
-    len: usize,
+use crate::config::{InvalidUtf8, LongLines};
+
+pub enum Line {
+    FullLine(String),
+    PartialLine(String),
+    EOF(String),
+    /// Line with invalid UTF-8 when using `InvalidUtf8::Raw`
+    Raw(Vec<u8>),
 }
 
-impl Drop for Guard<'_> {
-    fn drop(&mut self) {
-        unsafe {
-            self.buf.set_len(self.len);
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Line::PartialLine(s) => write!(f, "PartialLine({})", s),
+            Line::FullLine(s) => write!(f, "FullLine({})", s),
+            Line::EOF(s) => write!(f, "EOF({})", s),
+            Line::Raw(bytes) => write!(f, "Raw({})", escape_invalid_utf8(bytes)),
         }
     }
 }
 
-fn to_usize(i: isize) -> usize {
-    usize::try_from(i).unwrap_or(0)
-}
+/// Replace invalid UTF-8 sequences with `\xNN` escapes
+pub fn escape_invalid_utf8(mut bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len());
 
-fn to_isize(i: usize) -> isize {
-    isize::try_from(i).unwrap_or(0)
+    loop {
+        match from_utf8(bytes) {
+            Ok(valid) => {
+                out.push_str(valid);
+                return out;
+            }
+            Err(err) => {
+                let (valid, rest) = bytes.split_at(err.valid_up_to());
+                // Safe to unwrap since the slice was just validated
+                out.push_str(from_utf8(valid).unwrap());
+
+                let invalid_len = err.error_len().unwrap_or(rest.len());
+                for byte in &rest[..invalid_len] {
+                    out.push_str(&format!("\\x{:02X}", byte));
+                }
+
+                bytes = &rest[invalid_len..];
+            }
+        }
+    }
 }
 
-impl Line {
-    pub fn as_line<'a>(&'a self) -> &'a str {
-        match self {
-            Line::PartialLine(s) => &s,
-            Line::FullLine(s) => &s,
-            Line::EOF(s) => &s,
+/// Split the lines which were read whole into parts like the reader splits
+/// long lines. No part has more than `max_len` bytes between newlines, not
+/// counting the newline. Lines which fit are returned as is.
+pub fn split_long_line(line: Line, max_len: usize) -> Vec<Line> {
+    let max_len = max_len.max(1);
+    let (s, eof) = match &line {
+        Line::FullLine(s) | Line::PartialLine(s) => (s, false),
+        Line::EOF(s) => (s, true),
+        Line::Raw(_) => return vec![line],
+    };
+
+    let content = s.trim_end_matches('\n');
+    let mut cuts = Vec::new();
+    let mut line_start = 0;
+
+    for (i, c) in content.char_indices() {
+        if c == '\n' {
+            line_start = i + 1;
+        } else if i > line_start && i + c.len_utf8() - line_start > max_len {
+            cuts.push(i);
+            line_start = i;
         }
     }
 
-    pub fn len(&self) -> usize {
-        self.as_line().trim_end().len()
+    if cuts.is_empty() {
+        return vec![line];
     }
-}
 
-// https://github.com/rust-lang/rust/blob/b69f6e65c081f9a628ef5db83ba77e3861e60e60/src/libstd/io/mod.rs#L333-L349
-fn append_to_string<F>(buf: &mut String, f: F) -> Result<usize, Error>
-where
-    F: FnOnce(&mut Vec<u8>) -> Result<usize, Error>,
-{
-    unsafe {
-        let mut g = Guard {
-            len: buf.len(),
-            buf: buf.as_mut_vec(),
-        };
-        let ret = f(g.buf);
-        if from_utf8(&g.buf[g.len..]).is_err() {
-            ret.and_then(|_| {
-                Err(Error::new(
-                    ErrorKind::InvalidData,
-                    "stream did not contain valid UTF-8",
-                ))
-            })
-        } else {
-            g.len = g.buf.len();
-            ret
-        }
+    let mut parts = Vec::new();
+    let mut start = 0;
+    for cut in cuts {
+        parts.push(Line::PartialLine(s[start..cut].to_string()));
+        start = cut;
     }
-}
 
-pub enum Line {
-    FullLine(String),
-    PartialLine(String),
-    EOF(String),
+    let rest = s[start..].to_string();
+    parts.push(if eof {
+        Line::EOF(rest)
+    } else {
+        Line::PartialLine(rest)
+    });
+
+    parts
 }
 
-impl fmt::Display for Line {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Line::PartialLine(s) => write!(f, "PartialLine({})", s),
-            Line::FullLine(s) => write!(f, "FullLine({})", s),
-            Line::EOF(s) => write!(f, "EOF({})", s),
-        }
+/// Length of an incomplete multibyte character at the end of the bytes
+fn incomplete_char_len(bytes: &[u8]) -> usize {
+    match from_utf8(bytes) {
+        Err(err) if err.error_len().is_none() => bytes.len() - err.valid_up_to(),
+        _ => 0,
     }
 }
 
-enum Status {
-    Full(usize),
-    Partial(usize),
-    Missing(usize),
-    Error(usize, Error),
+enum Kind {
+    Full,
+    Partial,
+    End,
 }
 
+/// Splits the output into lines. Works with non-blocking readers too: a read
+/// failing with `ErrorKind::WouldBlock` keeps the incomplete line and the next
+/// call continues it.
 pub struct SafeLineReader<R> {
     inner: BufReader<R>,
     max_line_size: usize,
+    invalid_utf8: InvalidUtf8,
+    long_lines: LongLines,
+    line_memory_limit: usize,
     sent_partial: bool,
+    /// The line read so far. Starts with the multibyte character that was
+    /// split between partial lines.
+    buf: Vec<u8>,
+    /// Count of the dropped bytes while skipping the rest of a truncated line
+    dropped: Option<usize>,
 }
 
 impl<R: Read> SafeLineReader<R> {
@@ -99,107 +131,167 @@ impl<R: Read> SafeLineReader<R> {
         SafeLineReader {
             inner,
             max_line_size,
+            invalid_utf8: InvalidUtf8::Error,
+            long_lines: LongLines::Split,
+            line_memory_limit: usize::MAX,
             sent_partial: false,
+            buf: Vec::new(),
+            dropped: None,
+        }
+    }
+
+    pub fn set_invalid_utf8(&mut self, invalid_utf8: InvalidUtf8) {
+        self.invalid_utf8 = invalid_utf8;
+    }
+
+    /// In `LongLines::Unlimited` mode lines are split only when they exceed
+    /// the memory limit
+    pub fn set_long_lines(&mut self, long_lines: LongLines, line_memory_limit: usize) {
+        self.long_lines = long_lines;
+        self.line_memory_limit = line_memory_limit;
+    }
+
+    fn decode(&mut self, mut buf: Vec<u8>, kind: Kind) -> Result<Line, Error> {
+        // Do not split multibyte characters between partial lines
+        if let Kind::Partial = kind {
+            let incomplete = incomplete_char_len(&buf);
+            if incomplete > 0 && incomplete < buf.len() {
+                self.buf = buf.split_off(buf.len() - incomplete);
+            }
         }
+
+        let s = match String::from_utf8(buf) {
+            Ok(s) => s,
+            Err(err) => match self.invalid_utf8 {
+                InvalidUtf8::Error => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "stream did not contain valid UTF-8",
+                    ));
+                }
+                InvalidUtf8::Lossy => String::from_utf8_lossy(err.as_bytes()).into_owned(),
+                InvalidUtf8::Escape => escape_invalid_utf8(err.as_bytes()),
+                InvalidUtf8::Raw => return Ok(Line::Raw(err.into_bytes())),
+            },
+        };
+
+        Ok(match kind {
+            Kind::Full => Line::FullLine(s),
+            Kind::Partial => Line::PartialLine(s),
+            Kind::End => Line::EOF(s),
+        })
+    }
+
+    /// Line limit for the current long line policy
+    fn line_limit(&self) -> usize {
+        match self.long_lines {
+            LongLines::Unlimited => self.line_memory_limit,
+            LongLines::Split | LongLines::Truncate => self.max_line_size,
+        }
+    }
+
+    fn truncated(&mut self, mut buf: Vec<u8>, mut dropped: usize) -> Result<Line, Error> {
+        let incomplete = incomplete_char_len(&buf);
+        buf.truncate(buf.len() - incomplete);
+        dropped += incomplete;
+
+        buf.extend_from_slice(format!("…({} bytes dropped)\n", dropped).as_bytes());
+        self.decode(buf, Kind::Full)
+    }
+
+    /// Emit the incomplete line, such as a prompt which is not followed by
+    /// a newline, when the process has stopped writing
+    pub fn flush_partial(&mut self) -> Option<Result<Line, Error>> {
+        if self.buf.is_empty() || self.dropped.is_some() {
+            return None;
+        }
+
+        self.sent_partial = true;
+        let buf = std::mem::take(&mut self.buf);
+        Some(self.decode(buf, Kind::Partial))
+    }
+
+    /// Has read a part of a line which could be flushed
+    pub fn has_partial(&self) -> bool {
+        !self.buf.is_empty() && self.dropped.is_none()
     }
 
     pub fn read_line(&mut self) -> Result<Line, Error> {
-        // b'\n'
-        let mut buf = String::new();
+        let limit = self.line_limit();
 
         loop {
-            let status = {
-                let available = match self.inner.fill_buf() {
-                    Ok(n) => n,
-                    Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
-                    Err(e) => return Err(e),
-                };
+            let available = match self.inner.fill_buf() {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            if available.is_empty() {
+                let buf = std::mem::take(&mut self.buf);
+
+                if let Some(dropped) = self.dropped.take() {
+                    return self.truncated(buf, dropped);
+                }
+
+                return self.decode(buf, Kind::End);
+            }
 
-                let space_available = to_usize(to_isize(self.max_line_size) - to_isize(buf.len()));
+            let newline = memchr::memchr(b'\n', available);
 
-                match memchr::memchr(b'\n', available) {
+            if let Some(dropped) = self.dropped {
+                match newline {
                     Some(i) => {
-                        let overflow =
-                            to_isize(self.max_line_size) - (to_isize(buf.len()) + to_isize(i));
-
-                        if overflow >= 0 {
-                            let res = append_to_string(&mut buf, |b| {
-                                b.extend_from_slice(&available[..=i]);
-                                Ok(available[..=i].len())
-                            });
-
-                            if let Err(err) = res {
-                                Status::Error(i + 1, err)
-                            } else if self.sent_partial {
-                                self.sent_partial = false;
-                                Status::Partial(i + 1)
-                            } else {
-                                Status::Full(i + 1)
-                            }
-                        } else {
-                            let remaining = cmp::min(space_available, i + 1);
-                            let res = append_to_string(&mut buf, |b| {
-                                b.extend_from_slice(&available[..remaining]);
-                                Ok(available[..remaining].len())
-                            });
-
-                            if let Err(err) = res {
-                                Status::Error(i + 1, err)
-                            } else {
-                                self.sent_partial = true;
-                                Status::Partial(remaining)
-                            }
-                        }
+                        self.inner.consume(i + 1);
+                        self.dropped = None;
+                        let buf = std::mem::take(&mut self.buf);
+                        return self.truncated(buf, dropped + i);
                     }
                     None => {
-                        let overflow = to_isize(self.max_line_size)
-                            - (to_isize(buf.len()) + to_isize(available.len()));
-                        if overflow < 0 {
-                            let res = append_to_string(&mut buf, |b| {
-                                b.extend_from_slice(&available[..space_available]);
-                                Ok(available[..space_available].len())
-                            });
-
-                            if let Err(err) = res {
-                                Status::Error(space_available, err)
-                            } else {
-                                self.sent_partial = true;
-                                Status::Partial(space_available)
-                            }
-                        } else {
-                            let res = append_to_string(&mut buf, |b| {
-                                b.extend_from_slice(available);
-                                Ok(available.len())
-                            });
-
-                            if let Err(err) = res {
-                                Status::Error(available.len(), err)
-                            } else {
-                                Status::Missing(available.len())
-                            }
-                        }
+                        let used = available.len();
+                        self.dropped = Some(dropped + used);
+                        self.inner.consume(used);
+                        continue;
                     }
                 }
+            }
+
+            // The newline itself is not counted
+            let fits = match newline {
+                Some(i) => self.buf.len() + i <= limit,
+                None => self.buf.len() + available.len() <= limit,
             };
 
-            match status {
-                Status::Full(used) => {
-                    self.inner.consume(used);
-                    return Ok(Line::FullLine(buf));
+            match newline {
+                Some(i) if fits => {
+                    self.buf.extend_from_slice(&available[..=i]);
+                    self.inner.consume(i + 1);
+                    let buf = std::mem::take(&mut self.buf);
+
+                    if self.sent_partial {
+                        self.sent_partial = false;
+                        return self.decode(buf, Kind::Partial);
+                    }
+
+                    return self.decode(buf, Kind::Full);
                 }
-                Status::Partial(used) => {
+                None if fits => {
+                    let used = available.len();
+                    self.buf.extend_from_slice(available);
                     self.inner.consume(used);
-                    return Ok(Line::PartialLine(buf));
                 }
-                Status::Missing(used) => {
-                    if used == 0 {
-                        return Ok(Line::EOF(buf));
+                _ => {
+                    let space_available = limit.saturating_sub(self.buf.len());
+                    self.buf.extend_from_slice(&available[..space_available]);
+                    self.inner.consume(space_available);
+
+                    if self.long_lines == LongLines::Truncate {
+                        self.dropped = Some(0);
+                        continue;
                     }
-                    self.inner.consume(used);
-                }
-                Status::Error(used, err) => {
-                    self.inner.consume(used);
-                    return Err(err);
+
+                    self.sent_partial = true;
+                    let buf = std::mem::take(&mut self.buf);
+                    return self.decode(buf, Kind::Partial);
                 }
             }
         }
@@ -212,6 +304,7 @@ fn get_full_line(s: Line) -> String {
         Line::FullLine(s) => s,
         Line::PartialLine(s) => panic!("Expected full line but got partial with: `{}`", s),
         Line::EOF(s) => panic!("Expected full line but got EOF with: `{}`", s),
+        line => panic!("Expected full line but got {}", line),
     }
 }
 
@@ -221,6 +314,7 @@ fn get_partial_line(s: Line) -> String {
         Line::PartialLine(s) => s,
         Line::FullLine(s) => panic!("Expected partial line but got full with: `{}`", s),
         Line::EOF(s) => panic!("Expected partial line but got EOF with: `{}`", s),
+        line => panic!("Expected partial line but got {}", line),
     }
 }
 
@@ -394,3 +488,185 @@ fn long_line_in_the_middle() {
     let s = get_full_line(reader.read_line().unwrap());
     assert_eq!(s, "bar\n");
 }
+
+#[cfg(test)]
+fn reader_with_mode(in_buf: &[u8], invalid_utf8: InvalidUtf8) -> SafeLineReader<&[u8]> {
+    let mut reader = SafeLineReader::new(BufReader::with_capacity(2, in_buf), 100);
+    reader.set_invalid_utf8(invalid_utf8);
+    reader
+}
+
+#[test]
+fn invalid_unicode_lossy() {
+    let mut reader = reader_with_mode(b"caf\xe9\n", InvalidUtf8::Lossy);
+
+    let s = get_full_line(reader.read_line().unwrap());
+    assert_eq!(s, "caf\u{FFFD}\n");
+}
+
+#[test]
+fn invalid_unicode_escaped() {
+    let mut reader = reader_with_mode(b"caf\xe9 \xff\xfe\n", InvalidUtf8::Escape);
+
+    let s = get_full_line(reader.read_line().unwrap());
+    assert_eq!(s, "caf\\xE9 \\xFF\\xFE\n");
+}
+
+#[test]
+fn invalid_unicode_raw() {
+    let mut reader = reader_with_mode(b"caf\xe9\nok\n", InvalidUtf8::Raw);
+
+    match reader.read_line().unwrap() {
+        Line::Raw(bytes) => assert_eq!(bytes, b"caf\xe9\n"),
+        line => panic!("Expected raw line but got {}", line),
+    }
+
+    let s = get_full_line(reader.read_line().unwrap());
+    assert_eq!(s, "ok\n");
+}
+
+#[test]
+fn multibyte_character_split_by_buffer() {
+    // Each character is two bytes and the buffer is three bytes
+    let in_buf: &[u8] = "ääää\n".as_bytes();
+    let mut reader = SafeLineReader::new(BufReader::with_capacity(3, in_buf), 100);
+
+    let s = get_full_line(reader.read_line().unwrap());
+    assert_eq!(s, "ääää\n");
+}
+
+#[test]
+fn multibyte_character_is_not_split_between_partial_lines() {
+    let in_buf: &[u8] = "aää\n".as_bytes();
+    let mut reader = SafeLineReader::new(BufReader::with_capacity(100, in_buf), 4);
+
+    let s = get_partial_line(reader.read_line().unwrap());
+    assert_eq!(s, "aä");
+
+    let s = get_partial_line(reader.read_line().unwrap());
+    assert_eq!(s, "ä\n");
+}
+
+#[test]
+fn truncates_long_lines() {
+    let in_buf: &[u8] = b"too long line\nshort\n";
+    let mut reader = SafeLineReader::new(BufReader::with_capacity(3, in_buf), 7);
+    reader.set_long_lines(LongLines::Truncate, usize::MAX);
+
+    let s = get_full_line(reader.read_line().unwrap());
+    assert_eq!(s, "too lon…(6 bytes dropped)\n");
+
+    let s = get_full_line(reader.read_line().unwrap());
+    assert_eq!(s, "short\n");
+}
+
+#[test]
+fn truncates_long_line_at_eof() {
+    let in_buf: &[u8] = b"too long line";
+    let mut reader = SafeLineReader::new(BufReader::with_capacity(100, in_buf), 7);
+    reader.set_long_lines(LongLines::Truncate, usize::MAX);
+
+    let s = get_full_line(reader.read_line().unwrap());
+    assert_eq!(s, "too lon…(6 bytes dropped)\n");
+
+    let line = reader.read_line().unwrap();
+    assert_eq!(format!("{}", line), "EOF()");
+}
+
+#[test]
+fn truncate_does_not_split_multibyte_characters() {
+    let in_buf: &[u8] = "aää\n".as_bytes();
+    let mut reader = SafeLineReader::new(BufReader::with_capacity(100, in_buf), 4);
+    reader.set_long_lines(LongLines::Truncate, usize::MAX);
+
+    let s = get_full_line(reader.read_line().unwrap());
+    assert_eq!(s, "aä…(2 bytes dropped)\n");
+}
+
+#[test]
+fn unlimited_lines_are_split_only_at_memory_limit() {
+    let in_buf: &[u8] = b"too long line\n";
+    let mut reader = SafeLineReader::new(BufReader::with_capacity(3, in_buf), 3);
+    reader.set_long_lines(LongLines::Unlimited, 10);
+
+    let s = get_partial_line(reader.read_line().unwrap());
+    assert_eq!(s, "too long l");
+
+    let s = get_partial_line(reader.read_line().unwrap());
+    assert_eq!(s, "ine\n");
+}
+
+#[cfg(test)]
+fn nonblocking_pipe() -> (std::fs::File, std::fs::File) {
+    use nix::fcntl::OFlag;
+    use std::os::unix::io::FromRawFd;
+
+    let (read_fd, write_fd) = nix::unistd::pipe2(OFlag::O_NONBLOCK).unwrap();
+    unsafe {
+        (
+            std::fs::File::from_raw_fd(read_fd),
+            std::fs::File::from_raw_fd(write_fd),
+        )
+    }
+}
+
+#[test]
+fn splits_long_lines_read_whole() {
+    let parts = split_long_line(Line::FullLine(String::from("12345678\n")), 3);
+    let parts: Vec<String> = parts.into_iter().map(get_partial_line).collect();
+    assert_eq!(parts, vec!["123", "456", "78\n"]);
+
+    let line = split_long_line(Line::FullLine(String::from("123\n")), 3).remove(0);
+    assert_eq!(get_full_line(line), "123\n");
+
+    // Multiline events are split only where a line is too long
+    let parts = split_long_line(Line::FullLine(String::from("ab\ncdef\n")), 3);
+    let parts: Vec<String> = parts.into_iter().map(get_partial_line).collect();
+    assert_eq!(parts, vec!["ab\ncde", "f\n"]);
+
+    // Multibyte characters are kept whole
+    let parts = split_long_line(Line::FullLine(String::from("aäb\n")), 2);
+    let parts: Vec<String> = parts.into_iter().map(get_partial_line).collect();
+    assert_eq!(parts, vec!["a", "ä", "b\n"]);
+}
+
+#[test]
+fn continues_line_after_would_block() {
+    use std::io::Write;
+
+    let (read_end, mut write_end) = nonblocking_pipe();
+    let mut reader = SafeLineReader::new(BufReader::new(read_end), 100);
+
+    write_end.write_all(b"hel").unwrap();
+    let err = reader.read_line().err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::WouldBlock);
+
+    write_end.write_all(b"lo\nnext").unwrap();
+    let s = get_full_line(reader.read_line().unwrap());
+    assert_eq!(s, "hello\n");
+
+    drop(write_end);
+    let line = reader.read_line().unwrap();
+    assert_eq!(format!("{}", line), "EOF(next)");
+}
+
+#[test]
+fn flushes_incomplete_line() {
+    use std::io::Write;
+
+    let (read_end, mut write_end) = nonblocking_pipe();
+    let mut reader = SafeLineReader::new(BufReader::new(read_end), 100);
+
+    write_end.write_all(b"Password: ").unwrap();
+    assert!(reader.read_line().is_err());
+    assert!(reader.has_partial());
+
+    let s = get_partial_line(reader.flush_partial().unwrap().unwrap());
+    assert_eq!(s, "Password: ");
+    assert!(reader.flush_partial().is_none());
+
+    write_end.write_all(b"ok\n").unwrap();
+
+    let s = get_partial_line(reader.read_line().unwrap());
+    assert_eq!(s, "ok\n");
+}
@@ -1,10 +1,52 @@
+use lazy_static::lazy_static;
+use serde_json::json;
+use std::sync::{Arc, Mutex};
+
+use crate::output_queue::OutputQueue;
+
+lazy_static! {
+    static ref JSON_OUTPUT: bool = std::env::var("MULTIP_OUTPUT")
+        .map(|format| format == "json")
+        .unwrap_or(false);
+    static ref OUTPUT: Mutex<Option<Arc<OutputQueue>>> = Mutex::new(None);
+}
+
+/// Print the messages with the printer thread so they stay in order with the
+/// output of the processes and logging never waits for stdout
+pub fn set_output(queue: Option<Arc<OutputQueue>>) {
+    *OUTPUT.lock().unwrap() = queue;
+}
+
+/// Is the output formatted as JSON lines instead of the `[name]` prefixes
+pub fn is_json() -> bool {
+    *JSON_OUTPUT
+}
+
+pub fn print_log(msg: String) {
+    let text = if is_json() {
+        let record = json!({
+            "process": "multip",
+            "pid": std::process::id(),
+            "message": msg,
+        });
+        record.to_string()
+    } else {
+        msg
+    };
+
+    match OUTPUT.lock().unwrap().as_ref() {
+        Some(queue) => queue.push_log(text),
+        None => println!("{}", text),
+    }
+}
+
 #[macro_export]
 macro_rules! log {
     () => {
         println!();
     };
     ($($arg:tt)+) => {
-        println!($($arg)*);
+        $crate::log::print_log(format!($($arg)*));
     }
 }
 
@@ -17,8 +59,7 @@ macro_rules! debug {
     };
     ($($arg:tt)+) => {
         if std::env::var("MULTIP_DEBUG").is_ok() {
-            print!("<DEBUG> ");
-            println!($($arg)*);
+            $crate::log::print_log(format!("<DEBUG> {}", format!($($arg)*)));
         }
     }
 }
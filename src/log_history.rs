@@ -0,0 +1,100 @@
+use std::collections::VecDeque;
+
+use crate::control::Subscriber;
+
+struct Follower {
+    names: Vec<String>,
+    subscriber: Subscriber,
+}
+
+impl Follower {
+    fn wants(&self, name: &str) -> bool {
+        self.names.is_empty() || self.names.iter().any(|n| n == name)
+    }
+}
+
+/// The last printed lines of every process for `multip ctl logs`
+pub struct LogHistory {
+    capacity: usize,
+    /// Lines are numbered so the output of several processes can be shown in
+    /// the order it was printed
+    lines: Vec<(String, VecDeque<(u64, String)>)>,
+    next_seq: u64,
+    followers: Vec<Follower>,
+}
+
+impl LogHistory {
+    pub fn new(capacity: usize) -> LogHistory {
+        LogHistory {
+            capacity,
+            lines: Vec::new(),
+            next_seq: 0,
+            followers: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, name: &str, text: String) {
+        // Forget the followers that have gone away
+        self.followers
+            .retain(|follower| !follower.wants(name) || follower.subscriber.send(text.clone()));
+
+        if self.capacity == 0 {
+            return;
+        }
+
+        let index = match self.lines.iter().position(|(n, _)| n == name) {
+            Some(index) => index,
+            None => {
+                self.lines.push((name.to_string(), VecDeque::new()));
+                self.lines.len() - 1
+            }
+        };
+
+        let lines = &mut self.lines[index].1;
+        if lines.len() >= self.capacity {
+            lines.pop_front();
+        }
+        lines.push_back((self.next_seq, text));
+        self.next_seq += 1;
+    }
+
+    /// Recent lines of the given processes or all of them when empty
+    pub fn recent(&self, names: &[String]) -> String {
+        let mut lines: Vec<&(u64, String)> = self
+            .lines
+            .iter()
+            .filter(|(name, _)| names.is_empty() || names.contains(name))
+            .flat_map(|(_, lines)| lines.iter())
+            .collect();
+
+        lines.sort_by_key(|(seq, _)| *seq);
+        lines.iter().map(|(_, text)| text.as_str()).collect()
+    }
+
+    /// Stream the new lines of the given processes to the subscriber
+    pub fn follow(&mut self, names: Vec<String>, subscriber: Subscriber) {
+        self.followers.push(Follower { names, subscriber });
+    }
+}
+
+#[test]
+fn keeps_last_lines_per_process() {
+    let mut history = LogHistory::new(2);
+
+    history.push("a", String::from("a1\n"));
+    history.push("b", String::from("b1\n"));
+    history.push("a", String::from("a2\n"));
+    history.push("a", String::from("a3\n"));
+
+    assert_eq!(history.recent(&[]), "b1\na2\na3\n");
+    assert_eq!(history.recent(&[String::from("a")]), "a2\na3\n");
+    assert_eq!(history.recent(&[String::from("c")]), "");
+}
+
+#[test]
+fn history_can_be_disabled() {
+    let mut history = LogHistory::new(0);
+
+    history.push("a", String::from("a1\n"));
+    assert_eq!(history.recent(&[]), "");
+}
@@ -1,34 +1,112 @@
 #[cfg(target_os = "linux")]
 use libc::{prctl, PR_SET_CHILD_SUBREAPER};
 
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
 use nix::sys::signal;
-use nix::sys::signal::kill;
 use nix::sys::signal::Signal;
+use nix::sys::signal::{kill, killpg, SigSet};
 use nix::unistd::Pid;
 use std::env;
 use std::fmt;
-use std::io::{BufReader, Error, Read};
-use std::marker::Send;
+use std::fs::File;
+use std::io::{self, BufReader, Error, ErrorKind, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::panic::{self, AssertUnwindSafe};
 use std::process::{id, Command, Stdio};
-use std::sync::mpsc;
 use std::sync::mpsc::RecvTimeoutError;
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
-
+use std::time::{Duration, Instant, SystemTime};
+
+use cgroup::ChildCgroup;
+use config::{BufferFull, InvalidUtf8, LongLines, Overlap, Passthrough, ProcessConfig};
+use cron::Schedule;
+use event_loop::{Channel, EventLoop};
+use filter::OutputFilter;
+use log_history::LogHistory;
+use metrics::Metrics;
+use multiline::MultilineGrouper;
+use output_queue::{Entry, OutputQueue};
+use pidfd::PidFd;
+use regex::Regex;
+use rusage::ResourceUsage;
+
+mod cgroup;
+mod check;
+mod config;
+mod control;
+mod cron;
+mod event_loop;
+mod filter;
+mod health;
+mod http;
+mod json;
 mod line_reader;
 mod log;
-mod signal_closure;
+mod log_history;
+mod metrics;
+mod multiline;
+mod output_queue;
+mod pidfd;
+mod procfs;
+mod rusage;
 mod waitpid;
+mod watch;
+
+#[derive(Clone, Copy)]
+enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl Stream {
+    fn as_str(self) -> &'static str {
+        match self {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        }
+    }
+}
 
 struct Line {
     name: String,
+    pid: u32,
+    stream: Stream,
     line: Result<line_reader::Line, Error>,
 }
 
 impl Line {
     fn print(&self) {
-        print!("{}", self);
+        if log::is_json() {
+            if let Some(record) = json::format_line(self) {
+                println!("{}", record);
+            }
+        } else if let Ok(line_reader::Line::Raw(bytes)) = &self.line {
+            let stdout = io::stdout();
+            let mut out = stdout.lock();
+            let bytes = bytes.strip_suffix(b"\n").unwrap_or(bytes);
+            let res = write!(out, "[{}] ", self.name)
+                .and_then(|_| out.write_all(bytes))
+                .and_then(|_| writeln!(out));
+
+            if let Err(err) = res {
+                eprintln!("Failed to write raw line from [{}]: {}", self.name, err);
+            }
+        } else {
+            print!("{}", self);
+        }
+    }
+
+    /// The line as printed for `multip ctl logs`
+    fn log_text(&self) -> String {
+        if log::is_json() {
+            json::format_line(self)
+                .map(|record| record + "\n")
+                .unwrap_or_default()
+        } else {
+            self.to_string()
+        }
     }
 }
 
@@ -47,9 +125,287 @@ impl fmt::Display for Line {
                     write!(f, "")
                 }
             }
-            Ok(line_reader::Line::FullLine(s)) => writeln!(f, "[{}] {}", self.name, s.trim_end()),
+            Ok(line_reader::Line::FullLine(s)) => {
+                // Multiline events are printed with the prefix on every line
+                for s in s.trim_end().split('\n') {
+                    writeln!(f, "[{}] {}", self.name, s.trim_end())?;
+                }
+                Ok(())
+            }
+            Ok(line_reader::Line::Raw(bytes)) => {
+                let s = String::from_utf8_lossy(bytes);
+                writeln!(f, "[{}] {}", self.name, s.trim_end())
+            }
+        }
+    }
+}
+
+/// Compiled output options of a process
+struct OutputRules {
+    filter: OutputFilter,
+    multiline: Option<MultilineGrouper>,
+    invalid_utf8: InvalidUtf8,
+    max_line_length: usize,
+    long_lines: LongLines,
+    line_memory_limit: usize,
+    idle_flush: Option<Duration>,
+    buffer_full: BufferFull,
+    ready_pattern: Option<Regex>,
+}
+
+impl OutputRules {
+    fn new(config: &ProcessConfig) -> Result<OutputRules, String> {
+        let multiline = match &config.multiline {
+            Some(multiline) => Some(MultilineGrouper::new(&config.name, multiline)?),
+            None => None,
+        };
+
+        let ready_pattern =
+            match &config.ready_pattern {
+                Some(pattern) => Some(Regex::new(pattern).map_err(|err| {
+                    format!("Invalid ready_pattern for [{}]: {}", config.name, err)
+                })?),
+                None => None,
+            };
+
+        let max_line_length = config
+            .max_line_length
+            .unwrap_or_else(|| read_env_as_number("MULTIP_MAX_LINE_LENGTH", 1000));
+        if max_line_length == 0 {
+            return Err(String::from(
+                "Invalid MULTIP_MAX_LINE_LENGTH: must be at least 1",
+            ));
+        }
+
+        Ok(OutputRules {
+            filter: OutputFilter::new(config)?,
+            multiline,
+            invalid_utf8: config.invalid_utf8,
+            max_line_length,
+            long_lines: config.long_lines,
+            line_memory_limit: config.line_memory_limit.unwrap_or(1024 * 1024),
+            idle_flush: config.idle_flush_ms.map(Duration::from_millis),
+            buffer_full: config.buffer_full,
+            ready_pattern,
+        })
+    }
+
+    /// Lines are read whole and split after the redactions so a secret is
+    /// not split between the parts
+    fn splits_after_filter(&self) -> bool {
+        self.long_lines == LongLines::Split && self.filter.has_redactions()
+    }
+
+    fn is_ready_line(&self, line: &line_reader::Line) -> bool {
+        let re = match &self.ready_pattern {
+            Some(re) => re,
+            None => return false,
+        };
+
+        match line {
+            line_reader::Line::FullLine(s)
+            | line_reader::Line::PartialLine(s)
+            | line_reader::Line::EOF(s) => re.is_match(s),
+            line_reader::Line::Raw(bytes) => re.is_match(&String::from_utf8_lossy(bytes)),
+        }
+    }
+}
+
+/// Read from one stream at a time before letting the other events through
+const READ_BATCH: usize = 1000;
+
+/// Bytes read at once from the processes with `passthrough = "copy"`
+const COPY_BUFFER_BYTES: usize = 8 * 1024;
+
+enum StreamReader {
+    Lines(
+        Box<line_reader::SafeLineReader<File>>,
+        Option<MultilineGrouper>,
+    ),
+    /// Copied byte-for-byte without the line reader
+    Copy(File),
+}
+
+/// Result of a single read from an output stream
+enum StreamRead {
+    Lines(Vec<Result<line_reader::Line, Error>>),
+    Chunk(Vec<u8>),
+    WouldBlock,
+    Closed,
+}
+
+/// What to do with the stream after it has been read
+enum ReadState {
+    /// Wait until it is readable again
+    Drained,
+    /// Read again after the other events
+    More,
+    /// Wait until the printer has made room in the output queue
+    Full,
+    Closed,
+}
+
+/// Non-blocking output pipe of a child read by the main loop
+struct OutputStream {
+    kind: Stream,
+    /// The process the stream was opened for. The pipes of the previous runs
+    /// are read until the descendants holding them exit.
+    pid: u32,
+    fd: RawFd,
+    reader: StreamReader,
+    /// Output options when the process was started
+    output: Arc<OutputRules>,
+    /// In the epoll set of the event loop
+    registered: bool,
+    /// Not read while the output queue is full
+    paused: bool,
+    closed: bool,
+    /// Flush the incomplete line unless more output arrives before this
+    idle_deadline: Option<Instant>,
+}
+
+impl OutputStream {
+    fn new(
+        kind: Stream,
+        pid: u32,
+        stream: impl IntoRawFd,
+        output: &Arc<OutputRules>,
+        copy: bool,
+    ) -> nix::Result<OutputStream> {
+        let fd = stream.into_raw_fd();
+        let file = unsafe { File::from_raw_fd(fd) };
+        fcntl(fd, FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+
+        let reader = if copy {
+            StreamReader::Copy(file)
+        } else {
+            let mut reader =
+                line_reader::SafeLineReader::new(BufReader::new(file), output.max_line_length);
+            reader.set_invalid_utf8(output.invalid_utf8);
+            let long_lines = if output.splits_after_filter() {
+                LongLines::Unlimited
+            } else {
+                output.long_lines
+            };
+            reader.set_long_lines(long_lines, output.line_memory_limit);
+            StreamReader::Lines(Box::new(reader), output.multiline.clone())
+        };
+
+        Ok(OutputStream {
+            kind,
+            pid,
+            fd,
+            reader,
+            output: Arc::clone(output),
+            registered: false,
+            paused: false,
+            closed: false,
+            idle_deadline: None,
+        })
+    }
+
+    /// Is the reading stopped while the output queue is full instead of
+    /// dropping lines
+    fn waits_when_full(&self) -> bool {
+        matches!(self.reader, StreamReader::Copy(_)) || self.output.buffer_full == BufferFull::Block
+    }
+
+    fn read(&mut self) -> StreamRead {
+        if self.closed {
+            return StreamRead::Closed;
+        }
+
+        match &mut self.reader {
+            StreamReader::Copy(file) => {
+                let mut buf = vec![0; COPY_BUFFER_BYTES];
+
+                loop {
+                    match file.read(&mut buf) {
+                        Ok(0) => break,
+                        Ok(len) => {
+                            buf.truncate(len);
+                            return StreamRead::Chunk(buf);
+                        }
+                        Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
+                            return StreamRead::WouldBlock;
+                        }
+                        Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+                        Err(err) => {
+                            log!("Failed to read output: {}", err);
+                            break;
+                        }
+                    }
+                }
+
+                self.closed = true;
+                StreamRead::Closed
+            }
+            StreamReader::Lines(reader, grouper) => {
+                let line = match reader.read_line() {
+                    Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
+                        // Print pending output such as prompts which are not
+                        // followed by a newline when the child stops writing
+                        self.idle_deadline = match self.output.idle_flush {
+                            Some(timeout) if reader.has_partial() => Some(Instant::now() + timeout),
+                            _ => None,
+                        };
+                        return StreamRead::WouldBlock;
+                    }
+                    line => line,
+                };
+
+                // Invalid UTF-8 fails only the line
+                self.closed = match &line {
+                    Ok(line_reader::Line::EOF(_)) => true,
+                    Err(err) => err.kind() != ErrorKind::InvalidData,
+                    Ok(_) => false,
+                };
+
+                StreamRead::Lines(match grouper {
+                    Some(grouper) => multiline::group_line(grouper, line),
+                    None => vec![line],
+                })
+            }
         }
     }
+
+    /// When the incomplete line or the multiline event must be flushed
+    fn deadline(&self) -> Option<Instant> {
+        let grouped = match &self.reader {
+            StreamReader::Lines(_, Some(grouper)) => {
+                grouper.time_left().map(|left| Instant::now() + left)
+            }
+            _ => None,
+        };
+
+        self.idle_deadline.into_iter().chain(grouped).min()
+    }
+
+    /// The lines whose deadline has passed
+    fn flush_expired(&mut self, now: Instant) -> Vec<Result<line_reader::Line, Error>> {
+        let mut lines = Vec::new();
+
+        if let StreamReader::Lines(reader, grouper) = &mut self.reader {
+            if self.idle_deadline.is_some_and(|deadline| now >= deadline) {
+                self.idle_deadline = None;
+
+                if let Some(line) = reader.flush_partial() {
+                    match grouper {
+                        Some(grouper) => lines.extend(multiline::group_line(grouper, line)),
+                        None => lines.push(line),
+                    }
+                }
+            }
+
+            if let Some(grouper) = grouper {
+                if grouper.time_left().is_some_and(|left| left.is_zero()) {
+                    lines.extend(grouper.flush().map(Ok));
+                }
+            }
+        }
+
+        lines
+    }
 }
 
 fn read_env_as_number<N>(env: &str, default: N) -> N
@@ -63,95 +419,400 @@ where
 }
 
 enum Message {
-    Line(Line),
+    /// An output stream, the pidfd or the file watcher of a child is readable
+    Readable(RawFd),
+    /// The printer made room in the full output queue
+    OutputDrained,
     ParentSignal(Signal),
+    Control(control::Request, control::Reply),
+    /// GET request to the HTTP listener
+    Http(String, http::Reply),
 }
 
-type Channel = std::sync::mpsc::Sender<Message>;
-
-struct MultipChild<'a> {
-    name: &'a str,
+struct MultipChild {
+    config: ProcessConfig,
     kill_sent: Option<Signal>,
     is_dead: bool,
-    tx: &'a Channel,
+    queue: Arc<OutputQueue>,
+    output: Arc<OutputRules>,
     cmd: std::process::Child,
-    stdout_eof: Arc<Mutex<bool>>,
-    stderr_eof: Arc<Mutex<bool>>,
+    /// Used for signalling and noticing the exit when supported by the kernel
+    pidfd: Option<PidFd>,
+    /// The pidfd has been added to the event loop
+    exit_watched: bool,
+    cgroup: Option<ChildCgroup>,
+    /// OOM kills in the cgroup before the process was started
+    oom_kills: u64,
+    oom_killed: bool,
+    /// Output pipes until they are closed
+    streams: Vec<OutputStream>,
+    /// Set when the output matches the ready_pattern
+    ready_seen: bool,
+    started_at: Instant,
+    restarts: u32,
+    last_exit_code: Option<i32>,
+    /// Resource usage of the last exited process
+    last_usage: Option<ResourceUsage>,
+    /// Stopped with the control socket so the others are kept running
+    stop_requested: bool,
+    /// Start again once the stop completes
+    restart_requested: bool,
+    /// SIGKILL the process if it has not stopped by this time
+    stop_deadline: Option<Instant>,
+    /// Removed from the config. Forgotten once stopped.
+    removed: bool,
+    /// Watches the files of the process. Stops watching when dropped.
+    watcher: Option<watch::Watcher>,
 }
 
-impl fmt::Display for MultipChild<'_> {
+impl fmt::Display for MultipChild {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}({})", self.name, self.cmd.id())
+        write!(f, "{}({})", self.name(), self.cmd.id())
     }
 }
 
-impl MultipChild<'_> {
-    fn spawn<'a>(name: &'a str, command: &str, tx: &'a Channel) -> MultipChild<'a> {
-        let mut cmd = Command::new("/bin/sh")
-            .arg("-c")
-            // Add implicit exec to avoid extra process
-            .arg(format!("exec {}", command))
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()
-            .ok()
-            .expect("failed to spawn command");
+fn start_command(config: &ProcessConfig) -> io::Result<(std::process::Child, Option<ChildCgroup>)> {
+    let stdio = || {
+        if config.passthrough == Some(Passthrough::Inherit) {
+            Stdio::inherit()
+        } else {
+            Stdio::piped()
+        }
+    };
+
+    let mut command = Command::new("/bin/sh");
+    command
+        .arg("-c")
+        // Add implicit exec to avoid extra process
+        .arg(format!("exec {}", config.command))
+        .stdout(stdio())
+        .stderr(stdio());
+
+    if let Some(instance) = config.instance {
+        command.env("MULTIP_INSTANCE", instance.to_string());
+    }
+
+    if let Some(port) = config.port() {
+        command.env("PORT", port.to_string());
+    }
+
+    if config.process_group {
+        command.process_group(0);
+    }
+
+    // The signals read by the event loop are blocked in multip
+    unsafe {
+        command.pre_exec(|| {
+            SigSet::empty()
+                .thread_set_mask()
+                .map_err(|_| io::Error::last_os_error())
+        });
+    }
 
-        let stdout = cmd.stdout.take().expect("failed to take stdout");
-        let stderr = cmd.stderr.take().expect("failed to take stderr");
+    let cgroup = if config.has_cgroup_limits() {
+        let cgroup = cgroup::root()
+            .and_then(|root| root.create(config))
+            .map_err(io::Error::other)?;
+        Some(cgroup)
+    } else {
+        None
+    };
+
+    // Move the child to its cgroup before exec so everything it starts is
+    // in the cgroup too
+    let procs_file = match &cgroup {
+        Some(cgroup) => Some(cgroup.procs_file()?),
+        None => None,
+    };
+
+    if let Some(procs_file) = &procs_file {
+        let fd = procs_file.as_raw_fd();
+        unsafe {
+            command.pre_exec(move || {
+                // Only async-signal-safe calls are allowed here
+                if libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
 
-        let pid = cmd.id();
-        log!("Started [{}] with pid {}", name, pid);
+    let cmd = command.spawn()?;
 
-        let child = MultipChild {
-            name,
-            tx,
+    log!("Started [{}] with pid {}", config.name, cmd.id());
+
+    Ok((cmd, cgroup))
+}
+
+fn start_watcher(config: &ProcessConfig) -> Option<watch::Watcher> {
+    let watch = config.watch.as_ref()?;
+
+    match watch::watch(&config.name, watch) {
+        Ok(watcher) => Some(watcher),
+        Err(err) => {
+            log!("Not watching files for [{}]: {}", config.name, err);
+            None
+        }
+    }
+}
+
+impl MultipChild {
+    fn spawn(
+        config: ProcessConfig,
+        output: Arc<OutputRules>,
+        queue: &Arc<OutputQueue>,
+    ) -> io::Result<MultipChild> {
+        let (cmd, cgroup) = start_command(&config)?;
+
+        let watcher = start_watcher(&config);
+        let mut child = MultipChild {
+            config,
+            queue: Arc::clone(queue),
+            output,
             cmd,
+            pidfd: None,
+            exit_watched: false,
+            oom_kills: cgroup.as_ref().map_or(0, ChildCgroup::oom_kills),
+            cgroup,
+            oom_killed: false,
             is_dead: false,
             kill_sent: None,
-            stdout_eof: Arc::new(Mutex::new(false)),
-            stderr_eof: Arc::new(Mutex::new(false)),
+            streams: Vec::new(),
+            ready_seen: false,
+            started_at: Instant::now(),
+            restarts: 0,
+            last_exit_code: None,
+            last_usage: None,
+            stop_requested: false,
+            restart_requested: false,
+            stop_deadline: None,
+            removed: false,
+            watcher,
         };
 
-        child.monitor_ouput(Arc::clone(&child.stdout_eof), stdout);
-        child.monitor_ouput(Arc::clone(&child.stderr_eof), stderr);
+        child.monitor();
+        Ok(child)
+    }
+
+    /// Start a new process using the same config
+    fn respawn(&mut self) -> io::Result<()> {
+        let (cmd, cgroup) = start_command(&self.config)?;
 
-        child
+        // The limits were removed from the config
+        if let (Some(old), None) = (&self.cgroup, &cgroup) {
+            old.remove();
+        }
+
+        self.cmd = cmd;
+        self.oom_kills = cgroup.as_ref().map_or(0, ChildCgroup::oom_kills);
+        self.cgroup = cgroup;
+        self.oom_killed = false;
+        self.is_dead = false;
+        self.kill_sent = None;
+        self.started_at = Instant::now();
+        self.restarts += 1;
+        self.stop_requested = false;
+        self.restart_requested = false;
+        self.stop_deadline = None;
+        self.monitor();
+        Ok(())
     }
 
-    fn monitor_ouput(
-        &self,
-        eof_mutex: Arc<Mutex<bool>>,
-        stream: impl Read + Send + 'static,
-    ) -> std::thread::JoinHandle<()> {
-        let name = self.name.to_string();
-        let tx = mpsc::Sender::clone(self.tx);
-        thread::spawn(move || {
-            let buf = BufReader::new(stream);
+    /// Read the output of the new process in the main loop. Inherited
+    /// streams are not read.
+    fn monitor(&mut self) {
+        self.pidfd = PidFd::open(self.pid());
+        self.exit_watched = false;
+        self.ready_seen = false;
+
+        let pid = self.cmd.id();
+        let copy = self.config.passthrough == Some(Passthrough::Copy);
+
+        if let (Some(stdout), Some(stderr)) = (self.cmd.stdout.take(), self.cmd.stderr.take()) {
+            let streams = [
+                OutputStream::new(Stream::Stdout, pid, stdout, &self.output, copy),
+                OutputStream::new(Stream::Stderr, pid, stderr, &self.output, copy),
+            ];
+
+            for stream in streams {
+                match stream {
+                    Ok(stream) => self.streams.push(stream),
+                    Err(err) => {
+                        log!("Failed to read the output of {}: {}", self, err);
+                    }
+                }
+            }
+        }
+    }
 
-            let line_length = read_env_as_number("MULTIP_MAX_LINE_LENGTH", 1000);
+    /// Add the new pipes, the pidfd and the file watcher to the event loop
+    fn register(&mut self, events: &EventLoop) {
+        for stream in self.streams.iter_mut() {
+            if !stream.registered && !stream.paused {
+                events.add(stream.fd);
+                stream.registered = true;
+            }
+        }
 
-            let mut reader = line_reader::SafeLineReader::new(buf, line_length);
+        if !self.exit_watched {
+            if let Some(pidfd) = &self.pidfd {
+                events.add(pidfd.as_raw_fd());
+            }
+            self.exit_watched = true;
+        }
 
-            loop {
-                let name = name.to_string();
-                let line = reader.read_line();
+        if let Some(watcher) = &mut self.watcher {
+            if !watcher.registered {
+                events.add(watcher.as_raw_fd());
+                watcher.registered = true;
+            }
+        }
+    }
 
-                let exit = match line {
-                    Ok(line_reader::Line::EOF(_)) => true,
-                    _ => false,
-                };
+    fn name(&self) -> &str {
+        &self.config.name
+    }
 
-                tx.send(Message::Line(Line { name, line })).unwrap();
+    /// Queue a line read from the output of the process
+    fn emit(&mut self, stream: &OutputStream, line: Result<line_reader::Line, Error>) {
+        // Match before the filters so dropped lines count too
+        if let Ok(line) = &line {
+            if !self.ready_seen && stream.pid == self.cmd.id() && stream.output.is_ready_line(line)
+            {
+                self.ready_seen = true;
+            }
+        }
+
+        let lines = match line {
+            Ok(line) => match stream.output.filter.apply_line(line) {
+                Some(line) if stream.output.splits_after_filter() => {
+                    line_reader::split_long_line(line, stream.output.max_line_length)
+                        .into_iter()
+                        .map(Ok)
+                        .collect()
+                }
+                Some(line) => vec![Ok(line)],
+                None => return,
+            },
+            Err(err) => vec![Err(err)],
+        };
 
-                if exit {
-                    break;
+        for line in lines {
+            // Nothing to print for the end of the stream
+            if let Ok(line_reader::Line::EOF(s)) = &line {
+                if s.is_empty() {
+                    continue;
                 }
             }
 
-            let mut eof = eof_mutex.lock().unwrap();
-            *eof = true;
-        })
+            let line = Line {
+                name: self.name().to_string(),
+                pid: stream.pid,
+                stream: stream.kind,
+                line,
+            };
+
+            self.queue.push(line, stream.output.buffer_full);
+        }
+    }
+
+    fn read_stream(&mut self, stream: &mut OutputStream) -> ReadState {
+        for _ in 0..READ_BATCH {
+            if stream.waits_when_full() && self.queue.is_full() {
+                return ReadState::Full;
+            }
+
+            match stream.read() {
+                StreamRead::Lines(lines) => {
+                    for line in lines {
+                        self.emit(stream, line);
+                    }
+                }
+                StreamRead::Chunk(bytes) => self.queue.push_copy(stream.kind, bytes),
+                StreamRead::WouldBlock => return ReadState::Drained,
+                StreamRead::Closed => return ReadState::Closed,
+            }
+        }
+
+        ReadState::More
+    }
+
+    /// Read the output stream or the file events, or notice the exit of the
+    /// process
+    fn handle_readable(&mut self, fd: RawFd, events: &mut EventLoop) {
+        if let Some(watcher) = &mut self.watcher {
+            if watcher.as_raw_fd() == fd {
+                watcher.read_events();
+                return;
+            }
+        }
+
+        if self
+            .pidfd
+            .as_ref()
+            .is_some_and(|pidfd| pidfd.as_raw_fd() == fd)
+        {
+            // Stays readable after the exit. The children are reaped on every
+            // event.
+            events.remove(fd);
+            return;
+        }
+
+        let i = match self.streams.iter().position(|s| s.fd == fd && !s.paused) {
+            Some(i) => i,
+            None => return,
+        };
+
+        let mut stream = self.streams.swap_remove(i);
+
+        match self.read_stream(&mut stream) {
+            ReadState::Drained => {}
+            ReadState::More => events.defer(Message::Readable(fd)),
+            ReadState::Full => {
+                events.remove(fd);
+                stream.registered = false;
+                stream.paused = true;
+                stream.idle_deadline = None;
+            }
+            ReadState::Closed => {
+                events.remove(fd);
+                return;
+            }
+        }
+
+        self.streams.push(stream);
+    }
+
+    /// Continue reading the streams paused while the output queue was full
+    fn resume_output(&mut self, events: &mut EventLoop) {
+        for stream in self.streams.iter_mut().filter(|stream| stream.paused) {
+            stream.paused = false;
+            // Lines may be buffered already so the pipe is not necessarily
+            // readable
+            events.defer(Message::Readable(stream.fd));
+        }
+    }
+
+    /// Emit the incomplete lines and multiline events whose time is up
+    fn flush_expired_output(&mut self, now: Instant) {
+        let mut streams = std::mem::take(&mut self.streams);
+
+        for stream in streams.iter_mut() {
+            for line in stream.flush_expired(now) {
+                self.emit(stream, line);
+            }
+        }
+
+        self.streams = streams;
+    }
+
+    fn output_deadline(&self) -> Option<Instant> {
+        self.streams
+            .iter()
+            .filter(|stream| !stream.paused)
+            .filter_map(OutputStream::deadline)
+            .min()
     }
 
     fn pid(&self) -> Pid {
@@ -159,7 +820,7 @@ impl MultipChild<'_> {
     }
 
     fn kill(&mut self, sig: Signal) {
-        if !self.is_process_alive() {
+        if !self.can_be_signaled() {
             return;
         }
 
@@ -172,49 +833,121 @@ impl MultipChild<'_> {
 
         self.kill_sent = Some(sig);
 
+        if let Err(err) = self.send_signal(sig) {
+            log!("kill failed for [{}] {}", self.name(), err);
+        }
+    }
+
+    fn send_signal(&self, sig: Signal) -> nix::Result<()> {
         let pid = self.pid();
 
-        log!("Sending {} to {}({})", sig, self.name, pid);
-        if let Err(err) = kill(pid, sig) {
-            log!("kill failed for [{}] {}", self.name, err);
+        if self.config.process_group {
+            log!(
+                "Sending {} to process group of {}({})",
+                sig,
+                self.name(),
+                pid
+            );
+            killpg(pid, sig)
+        } else {
+            log!("Sending {} to {}({})", sig, self.name(), pid);
+            match &self.pidfd {
+                Some(pidfd) => pidfd.send_signal(sig),
+                None => kill(pid, sig),
+            }
         }
     }
 
-    fn is_process_alive(&self) -> bool {
-        !self.is_dead
+    /// Is the process or any process left in its group alive
+    fn can_be_signaled(&self) -> bool {
+        self.is_process_alive() || (self.config.process_group && killpg(self.pid(), None).is_ok())
     }
 
-    fn is_alive(&self) -> bool {
-        if !self.is_dead {
-            return true;
+    /// Forward the parent signal using the routing rules of the process.
+    /// Returns the signal sent.
+    fn forward_signal(&self, sig: Signal) -> Option<Signal> {
+        if !self.can_be_signaled() {
+            return None;
         }
 
-        let stdout_eof = self.stdout_eof.lock().unwrap();
-
-        if !*stdout_eof {
-            return true;
+        let routed = self.config.route_signal(sig)?;
+        match self.send_signal(routed) {
+            Ok(()) => Some(routed),
+            Err(err) => {
+                log!("kill failed for [{}] {}", self.name(), err);
+                None
+            }
         }
+    }
 
-        let stderr_eof = self.stderr_eof.lock().unwrap();
+    /// Stop the process without bringing the others down
+    fn stop(&mut self) {
+        self.stop_requested = true;
+        self.stop_deadline = Some(Instant::now() + self.config.stop_timeout());
+        self.kill(self.config.stop_signal());
+    }
 
-        if !*stderr_eof {
-            return true;
+    /// Record the exit of the reaped process
+    fn mark_exited(&mut self, exit_code: i32, usage: ResourceUsage) {
+        self.is_dead = true;
+        self.last_usage = Some(usage);
+        log!("Resource usage of {}: {}", self, usage);
+        self.check_oom_kill();
+        self.last_exit_code = Some(exit_code);
+    }
+
+    /// Check if the process died because its cgroup ran out of memory
+    fn check_oom_kill(&mut self) {
+        if let Some(cgroup) = &self.cgroup {
+            if cgroup.oom_kills() > self.oom_kills {
+                log!("Child {} was killed by the OOM killer", self);
+                self.oom_killed = true;
+            }
         }
+    }
 
-        return false;
+    /// Usage of the running process or the last exited one
+    fn usage(&self) -> Option<ResourceUsage> {
+        if self.is_process_alive() {
+            procfs::usage(self.pid())
+        } else {
+            self.last_usage
+        }
+    }
+
+    /// Running and the ready_pattern, if any, has been seen
+    fn is_ready(&self) -> bool {
+        self.is_process_alive()
+            && !self.stop_requested
+            && (self.config.ready_pattern.is_none() || self.ready_seen)
     }
-}
 
-fn command_with_name(s: &String) -> (&str, &str) {
-    let bytes = s.as_bytes();
+    /// Exited without being asked to stop. Failed jobs are only logged.
+    fn has_failed(&self) -> bool {
+        self.is_dead && !self.stop_requested && !self.config.is_job()
+    }
 
-    for (i, &item) in bytes.iter().enumerate() {
-        if item == b':' {
-            return (&s[0..i], (&s[i + 1..]).trim());
+    fn state(&self) -> &'static str {
+        match (self.is_dead, self.stop_requested) {
+            (false, false) => "running",
+            (false, true) => "stopping",
+            (true, true) => "stopped",
+            (true, false) => "exited",
         }
     }
 
-    panic!("cannot parse name from> {}", s);
+    fn is_process_alive(&self) -> bool {
+        !self.is_dead
+    }
+
+    /// Running or the output of the last run is still being read
+    fn is_alive(&self) -> bool {
+        !self.is_dead
+            || self
+                .streams
+                .iter()
+                .any(|stream| stream.pid == self.cmd.id())
+    }
 }
 
 #[cfg(not(target_os = "linux"))]
@@ -238,17 +971,535 @@ fn become_subreaper() -> Result<(), String> {
     }
 }
 
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (hours, mins, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+
+    if hours > 0 {
+        format!("{}h{}m{}s", hours, mins, secs)
+    } else if mins > 0 {
+        format!("{}m{}s", mins, secs)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+fn format_status(children: &[MultipChild]) -> String {
+    let mut out = format!(
+        "{:<16} {:>8} {:<9} {:>10} {:>8} {:>9} {:>9} {:>8} {:>8}\n",
+        "NAME", "PID", "STATE", "UPTIME", "RESTARTS", "LAST EXIT", "CPU", "MAX RSS", "CTX SW"
+    );
+
+    for child in children {
+        let (pid, uptime) = if child.is_process_alive() {
+            (
+                child.cmd.id().to_string(),
+                format_duration(child.started_at.elapsed()),
+            )
+        } else {
+            (String::from("-"), String::from("-"))
+        };
+
+        let last_exit = match child.last_exit_code {
+            _ if child.oom_killed => String::from("oom"),
+            Some(code) => code.to_string(),
+            None => String::from("-"),
+        };
+
+        let (cpu, max_rss, switches) = match child.usage() {
+            Some(usage) => (
+                usage.format_cpu(),
+                usage.format_max_rss(),
+                (usage.voluntary_switches + usage.involuntary_switches).to_string(),
+            ),
+            None => (String::from("-"), String::from("-"), String::from("-")),
+        };
+
+        out.push_str(&format!(
+            "{:<16} {:>8} {:<9} {:>10} {:>8} {:>9} {:>9} {:>8} {:>8}\n",
+            child.name(),
+            pid,
+            child.state(),
+            uptime,
+            child.restarts,
+            last_exit,
+            cpu,
+            max_rss,
+            switches
+        ));
+    }
+
+    out
+}
+
+fn find_child<'a>(
+    children: &'a mut [MultipChild],
+    name: &str,
+) -> Result<&'a mut MultipChild, String> {
+    children
+        .iter_mut()
+        .find(|child| child.name() == name && !child.removed)
+        .ok_or_else(|| format!("No such process: {}", name))
+}
+
+fn show_logs(
+    children: &[MultipChild],
+    history: &mut LogHistory,
+    names: Vec<String>,
+    follow: bool,
+    reply: control::Reply,
+) {
+    if let Some(name) = names
+        .iter()
+        .find(|&name| !children.iter().any(|child| child.name() == name))
+    {
+        reply.send(Err(format!("No such process: {}", name)));
+        return;
+    }
+
+    let recent = history.recent(&names);
+
+    if follow {
+        history.follow(names, reply.subscribe(recent));
+    } else {
+        reply.send(Ok(recent));
+    }
+}
+
+fn handle_control(
+    children: &mut [MultipChild],
+    request: control::Request,
+    shutting_down: bool,
+) -> Result<String, String> {
+    match request {
+        control::Request::Status => Ok(format_status(children)),
+
+        control::Request::Start(name) => {
+            if shutting_down {
+                return Err(String::from("multip is shutting down"));
+            }
+
+            let child = find_child(children, &name)?;
+            if child.is_process_alive() {
+                return Err(format!("{} is already running", name));
+            }
+
+            child
+                .respawn()
+                .map_err(|err| format!("Failed to start {}: {}", name, err))?;
+            Ok(format!("Started {}\n", child))
+        }
+
+        control::Request::Stop(name) => {
+            let child = find_child(children, &name)?;
+            if !child.is_process_alive() {
+                return Err(format!("{} is not running", name));
+            }
+
+            child.stop();
+            Ok(format!("Stopping {}\n", child))
+        }
+
+        control::Request::Restart(name) => {
+            if shutting_down {
+                return Err(String::from("multip is shutting down"));
+            }
+
+            let child = find_child(children, &name)?;
+            if child.is_process_alive() {
+                child.stop();
+                child.restart_requested = true;
+                Ok(format!("Restarting {}\n", child))
+            } else {
+                child
+                    .respawn()
+                    .map_err(|err| format!("Failed to start {}: {}", name, err))?;
+                Ok(format!("Started {}\n", child))
+            }
+        }
+
+        control::Request::Signal(name, sig) => {
+            let child = find_child(children, &name)?;
+            if !child.is_process_alive() {
+                return Err(format!("{} is not running", name));
+            }
+
+            child
+                .send_signal(sig)
+                .map_err(|err| format!("Failed to send {} to {}: {}", sig, child, err))?;
+            Ok(format!("Sent {} to {}\n", sig, child))
+        }
+
+        control::Request::Logs { .. } => unreachable!("logs are sent by show_logs"),
+    }
+}
+
+/// Read the processes from the config file and the command line
+fn load_processes(
+    config_path: Option<&String>,
+    cli_commands: &[&String],
+) -> Result<Vec<(ProcessConfig, OutputRules)>, String> {
+    let mut processes = match config_path {
+        Some(path) => config::load(path)?.process,
+        None => Vec::new(),
+    };
+
+    processes.extend(ProcessConfig::from_cli(cli_commands));
+
+    for process in processes.iter() {
+        process.validate()?;
+    }
+
+    let processes = config::expand_instances(processes);
+
+    if let Some(name) = config::find_duplicate(&processes) {
+        return Err(format!("Duplicate process name [{}]", name));
+    }
+
+    let mut loaded = Vec::new();
+    for process in processes {
+        let output = OutputRules::new(&process)?;
+
+        // Fail early if the limits cannot be applied. They are written
+        // only when the process is started.
+        if process.has_cgroup_limits() {
+            cgroup::check(&process)?;
+        }
+
+        loaded.push((process, output));
+    }
+
+    Ok(loaded)
+}
+
+/// Apply the reloaded config validated by load_processes. Unchanged
+/// processes are left running, changed ones are restarted, new ones started
+/// and removed ones stopped. Processes failing to start are only logged like
+/// failed restarts.
+fn reload(
+    children: &mut Vec<MultipChild>,
+    processes: Vec<(ProcessConfig, OutputRules)>,
+    jobs: &[Job],
+    queue: &Arc<OutputQueue>,
+) {
+    for child in children.iter_mut() {
+        // The jobs pick up their new config on the next run
+        if child.config.is_job() && jobs.iter().any(|job| job.config.name == child.name()) {
+            continue;
+        }
+
+        if !child.removed && !processes.iter().any(|(p, _)| p.name == child.name()) {
+            log!("Stopping [{}] removed from the config", child.name());
+            child.removed = true;
+            child.restart_requested = false;
+
+            if child.is_process_alive() {
+                child.stop();
+            } else {
+                child.stop_requested = true;
+            }
+        }
+    }
+
+    for (process, output) in processes {
+        let child = match children
+            .iter_mut()
+            .find(|child| child.name() == process.name)
+        {
+            Some(child) => child,
+            None => {
+                log!("Starting [{}] added to the config", process.name);
+                let name = process.name.clone();
+                match MultipChild::spawn(process, Arc::new(output), queue) {
+                    Ok(child) => children.push(child),
+                    Err(err) => {
+                        log!("Failed to start [{}]: {}", name, err);
+                    }
+                }
+                continue;
+            }
+        };
+
+        if child.config == process && !child.removed {
+            continue;
+        }
+
+        let was_removed = child.removed;
+        // The services are started right away unlike the jobs waiting for
+        // their next run
+        let was_job = child.config.is_job();
+        child.removed = false;
+
+        if child.is_process_alive() {
+            log!("Restarting [{}] because its config changed", child.name());
+            if !child.stop_requested {
+                child.stop();
+            }
+            child.restart_requested = true;
+        }
+
+        if child.config.watch != process.watch {
+            child.watcher = start_watcher(&process);
+        }
+
+        child.config = process;
+        child.output = Arc::new(output);
+
+        if (was_removed || was_job) && !child.is_process_alive() {
+            if let Err(err) = child.respawn() {
+                log!("Failed to start [{}]: {}", child.name(), err);
+            }
+        }
+    }
+}
+
+fn handle_http(children: &[MultipChild], metrics: &Metrics, path: &str) -> http::Response {
+    match path {
+        "/metrics" => {
+            http::Response::new(200, "text/plain; version=0.0.4", metrics.render(children))
+        }
+        "/healthz" => health::healthz(children),
+        "/readyz" => health::readyz(children),
+        _ => http::Response::not_found(),
+    }
+}
+
+/// Gracefully restart the process whose watched files changed or all the
+/// processes with restart_all
+fn restart_watched(children: &mut [MultipChild], name: &str, path: &str) {
+    let restart_all = match children
+        .iter()
+        .find(|child| child.name() == name && !child.removed)
+    {
+        Some(child) => child.config.watch.as_ref().is_some_and(|w| w.restart_all),
+        None => return,
+    };
+
+    for child in children.iter_mut() {
+        if child.removed || child.config.is_job() || (!restart_all && child.name() != name) {
+            continue;
+        }
+
+        // Leave the stopped processes and the ones already stopping alone
+        if !child.is_process_alive() || child.stop_requested {
+            continue;
+        }
+
+        log!("Restarting {} because {} changed", child, path);
+        child.stop();
+        child.restart_requested = true;
+    }
+}
+
+/// Process run on a cron schedule
+struct Job {
+    config: ProcessConfig,
+    output: Arc<OutputRules>,
+    schedule: Schedule,
+    next_run: Option<SystemTime>,
+}
+
+impl Job {
+    fn new(config: ProcessConfig, output: OutputRules) -> Job {
+        let schedule = config
+            .schedule
+            .as_deref()
+            .map(Schedule::parse)
+            .expect("job without a schedule")
+            .expect("schedule is validated");
+
+        Job {
+            next_run: schedule.next_after(SystemTime::now()),
+            schedule,
+            config,
+            output: Arc::new(output),
+        }
+    }
+}
+
+/// Separate the scheduled jobs from the processes which are started right
+/// away
+fn split_jobs(
+    processes: Vec<(ProcessConfig, OutputRules)>,
+) -> (Vec<(ProcessConfig, OutputRules)>, Vec<Job>) {
+    let (jobs, services): (Vec<_>, Vec<_>) = processes
+        .into_iter()
+        .partition(|(process, _)| process.is_job());
+
+    let jobs = jobs
+        .into_iter()
+        .map(|(process, output)| Job::new(process, output))
+        .collect();
+
+    (services, jobs)
+}
+
+/// Start a run of the job unless the previous run is still going
+fn run_job(children: &mut Vec<MultipChild>, job: &Job, queue: &Arc<OutputQueue>) {
+    let child = match children
+        .iter_mut()
+        .find(|child| child.name() == job.config.name)
+    {
+        Some(child) => child,
+        None => {
+            log!("Running job [{}]", job.config.name);
+            let output = Arc::clone(&job.output);
+            match MultipChild::spawn(job.config.clone(), output, queue) {
+                Ok(child) => children.push(child),
+                Err(err) => {
+                    log!("Failed to start job [{}]: {}", job.config.name, err);
+                }
+            }
+            return;
+        }
+    };
+
+    if child.is_process_alive() {
+        match job.config.overlap {
+            Overlap::Skip => {
+                log!(
+                    "Skipping job [{}] because {} is still running",
+                    job.config.name,
+                    child
+                );
+            }
+            Overlap::Restart => {
+                log!(
+                    "Restarting job [{}] because {} is still running",
+                    job.config.name,
+                    child
+                );
+                if !child.stop_requested {
+                    child.stop();
+                }
+                child.restart_requested = true;
+            }
+        }
+        return;
+    }
+
+    // Pick up the reloaded config
+    child.config = job.config.clone();
+    child.output = Arc::clone(&job.output);
+    child.removed = false;
+
+    log!("Running job [{}]", job.config.name);
+    if let Err(err) = child.respawn() {
+        log!("Failed to start job [{}]: {}", job.config.name, err);
+    }
+}
+
+/// Write the output copied byte-for-byte
+fn copy_output(stream: Stream, bytes: &[u8]) {
+    // Flush right away since stdout buffers incomplete lines which would get
+    // mixed with the lines from the other processes
+    let res = match stream {
+        Stream::Stdout => io::stdout()
+            .write_all(bytes)
+            .and_then(|_| io::stdout().flush()),
+        Stream::Stderr => io::stderr().write_all(bytes),
+    };
+
+    if let Err(err) = res {
+        eprintln!("Failed to write output: {}", err);
+    }
+}
+
+/// Write the queued output until the queue is closed. Runs in a thread of
+/// its own so the main loop keeps reading the processes while stdout is
+/// blocked.
+fn print_output(
+    queue: &OutputQueue,
+    history: &Mutex<LogHistory>,
+    metrics: &Mutex<Metrics>,
+    tx: &Channel,
+) {
+    while let Some((entries, was_full)) = queue.wait_take() {
+        if was_full {
+            tx.send(Message::OutputDrained).unwrap_or(());
+        }
+
+        for entry in entries {
+            let line = match entry {
+                Entry::Line(line) => line,
+                Entry::Log(text) => {
+                    println!("{}", text);
+                    continue;
+                }
+                Entry::Copy(stream, bytes) => {
+                    copy_output(stream, &bytes);
+                    continue;
+                }
+            };
+
+            line.print();
+            metrics.lock().unwrap().count_line(&line);
+
+            let text = line.log_text();
+            if !text.is_empty() {
+                history.lock().unwrap().push(&line.name, text);
+            }
+        }
+    }
+}
+
+fn print_dropped(queue: &OutputQueue, metrics: &Mutex<Metrics>) {
+    for (name, count) in queue.take_dropped() {
+        metrics.lock().unwrap().count_dropped(&name, count);
+        log!(
+            "Dropped {} lines from [{}] because the output buffer was full",
+            count,
+            name
+        );
+    }
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
 
-    for command in args[1..].iter() {
-        if command == "--version" {
+    if args.get(1).map(String::as_str) == Some("ctl") {
+        std::process::exit(control::client(&args[2..]));
+    }
+
+    if args.get(1).map(String::as_str) == Some("check") {
+        std::process::exit(check::run(&args[2..]));
+    }
+
+    let mut config_path: Option<&String> = None;
+    let mut cli_commands: Vec<&String> = Vec::new();
+    let mut args_iter = args[1..].iter();
+
+    while let Some(arg) = args_iter.next() {
+        if arg == "--version" {
             println!("version {}", option_env!("MULTIP_VERSION").unwrap_or("DEV"));
             println!("git rev {}", option_env!("GITHUB_SHA").unwrap_or("DEV"));
             return;
+        } else if arg == "--config" {
+            config_path = args_iter.next();
+            if config_path.is_none() {
+                eprintln!("--config requires a file path");
+                std::process::exit(1);
+            }
+        } else {
+            cli_commands.push(arg);
+        }
+    }
+
+    if let Ok(format) = env::var("MULTIP_OUTPUT") {
+        if format != "text" && format != "json" {
+            eprintln!("Unknown MULTIP_OUTPUT format: {}", format);
+            std::process::exit(1);
         }
     }
 
+    let processes = match load_processes(config_path, &cli_commands) {
+        Ok(processes) => processes,
+        Err(fail_msg) => {
+            eprintln!("{}", fail_msg);
+            std::process::exit(1);
+        }
+    };
+
     if let Err(fail_msg) = become_subreaper() {
         eprintln!("{}", fail_msg);
         std::process::exit(1);
@@ -256,45 +1507,125 @@ fn main() {
 
     log!("Started multip with pid {}", id());
 
-    let (tx, rx) = mpsc::channel::<Message>();
+    // Before starting any threads so the signals stay blocked in all of them
+    let mut signals = config::FORWARDED_SIGNALS.to_vec();
+    signals.push(signal::SIGCHLD);
+    let (mut events, tx) = match EventLoop::new(&signals) {
+        Ok(events) => events,
+        Err(fail_msg) => {
+            eprintln!("{}", fail_msg);
+            std::process::exit(1);
+        }
+    };
+
+    let queue = Arc::new(OutputQueue::new(read_env_as_number(
+        "MULTIP_BUFFER_LINES",
+        1000,
+    )));
+    let mut last_dropped_notice = Instant::now();
+    let history = Arc::new(Mutex::new(LogHistory::new(read_env_as_number(
+        "MULTIP_LOG_HISTORY_LINES",
+        100,
+    ))));
+    let metrics = Arc::new(Mutex::new(Metrics::default()));
+
+    let printer = {
+        let queue = Arc::clone(&queue);
+        let history = Arc::clone(&history);
+        let metrics = Arc::clone(&metrics);
+        let tx = Channel::clone(&tx);
+        thread::spawn(move || {
+            let res = panic::catch_unwind(AssertUnwindSafe(|| {
+                print_output(&queue, &history, &metrics, &tx)
+            }));
 
-    signal_closure::trap_signal(signal::SIGINT);
-    signal_closure::trap_signal(signal::SIGTERM);
-    signal_closure::trap_signal(signal::SIGQUIT);
-    signal_closure::trap_signal(signal::SIGCHLD);
+            // Exit like the main thread would when stdout cannot be written
+            if res.is_err() {
+                std::process::exit(101);
+            }
+        })
+    };
+    log::set_output(Some(Arc::clone(&queue)));
+
+    let control_socket = env::var(control::SOCKET_ENV).ok();
+    if let Some(path) = &control_socket {
+        if let Err(fail_msg) = control::listen(path, Channel::clone(&tx)) {
+            eprintln!("{}", fail_msg);
+            std::process::exit(1);
+        }
+    }
 
-    let t = mpsc::Sender::clone(&tx);
-    signal_closure::poll_signals(move |sig| {
-        t.send(Message::ParentSignal(sig)).unwrap();
-    });
+    let http_address = env::var(http::LISTEN_ENV)
+        .ok()
+        .map(|addr| http::parse_address(&addr));
+    if let Some(address) = &http_address {
+        if let Err(fail_msg) = http::listen(address, Channel::clone(&tx)) {
+            eprintln!("{}", fail_msg);
+            std::process::exit(1);
+        }
+    }
 
     let mut children: Vec<MultipChild> = Vec::new();
+    let (processes, mut jobs) = split_jobs(processes);
+    let mut killall: Option<Signal> = None;
+    let mut multip_exit_code: Option<i32> = None;
 
-    for command in args[1..].iter() {
-        let (name, command) = command_with_name(command);
-        let child = MultipChild::spawn(name, command, &tx);
-        children.push(child)
+    for (process, output) in processes {
+        let name = process.name.clone();
+        match MultipChild::spawn(process, Arc::new(output), &queue) {
+            Ok(child) => children.push(child),
+            Err(err) => {
+                // Bring down the processes started already
+                log!("Failed to start [{}]: {}", name, err);
+                killall = Some(Signal::SIGTERM);
+                multip_exit_code = Some(1);
+                break;
+            }
+        }
     }
 
-    let mut killall: Option<Signal> = None;
     let mut sigint_count = 0;
-    let mut multip_exit_code: Option<i32> = None;
+    // Got a signal asking multip itself to exit
+    let mut exit_requested = false;
+    // Time to SIGKILL the descendants left behind by the children
+    let mut stray_deadline: Option<Instant> = None;
+    // SIGKILLed strays which may still show up until they are gone
+    let mut killed_strays: Vec<Pid> = Vec::new();
+    let stray_timeout = Duration::from_millis(read_env_as_number("MULTIP_STRAY_TIMEOUT_MS", 5000));
+    // Check the state once before waiting for the first event
+    let mut msg: Result<Message, RecvTimeoutError> = Err(RecvTimeoutError::Timeout);
 
     loop {
-        // Manually check for dead children with the given timeout
-        let msg = rx.recv_timeout(Duration::from_millis(100));
-        let somebody_is_alive = children.iter().any(|child| child.is_alive());
         let mut forward: Option<Signal> = None;
 
         // Look for dead chilren on every event
         // AKA reap zombies
-        for (pid, exit_code) in waitpid::iter_dead_children() {
+        for (pid, exit_code, usage) in waitpid::iter_dead_children() {
             let child = children.iter_mut().find(|child| child.pid() == pid);
 
             match child {
+                Some(child) if child.stop_requested => {
+                    log!("Child {} stopped with exit code {}", child, exit_code);
+                    child.mark_exited(exit_code, usage);
+                    child.stop_deadline = None;
+
+                    if child.restart_requested && killall.is_none() && !exit_requested {
+                        if let Err(err) = child.respawn() {
+                            log!("Failed to restart [{}]: {}", child.name(), err);
+                        }
+                    }
+                }
+                Some(child) if child.config.is_job() => {
+                    if exit_code == 0 {
+                        log!("Job {} finished with exit code {}", child, exit_code);
+                    } else {
+                        log!("Job {} failed with exit code {}", child, exit_code);
+                    }
+                    child.mark_exited(exit_code, usage);
+                }
                 Some(child) => {
                     log!("Child {} died with exit code {}", child, exit_code);
-                    child.is_dead = true;
+                    child.mark_exited(exit_code, usage);
                     if killall.is_none() {
                         log!("Killing all other children too");
                         killall = Some(Signal::SIGTERM);
@@ -310,21 +1641,35 @@ fn main() {
                         pid,
                         exit_code
                     );
+                    metrics.lock().unwrap().count_zombie();
                 }
             }
         }
 
         match msg {
             Err(RecvTimeoutError::Timeout) => {
-                // loop tick
+                // A deadline was reached
             }
 
             Ok(Message::ParentSignal(Signal::SIGCHLD)) => {
                 // no-op signal just for looking dead children
             }
 
+            Ok(Message::Readable(fd)) => {
+                for child in children.iter_mut() {
+                    child.handle_readable(fd, &mut events);
+                }
+            }
+
+            Ok(Message::OutputDrained) => {
+                for child in children.iter_mut() {
+                    child.resume_output(&mut events);
+                }
+            }
+
             Ok(Message::ParentSignal(Signal::SIGINT)) => {
                 forward = Some(Signal::SIGINT);
+                exit_requested = true;
                 sigint_count += 1;
 
                 if sigint_count == 2 {
@@ -336,13 +1681,69 @@ fn main() {
                 }
             }
 
+            Ok(Message::ParentSignal(Signal::SIGHUP)) if config_path.is_some() => {
+                log!("Got SIGHUP, reloading the config");
+
+                let res = if killall.is_some() || exit_requested {
+                    Err(String::from("multip is shutting down"))
+                } else {
+                    load_processes(config_path, &cli_commands).map(|processes| {
+                        let (processes, new_jobs) = split_jobs(processes);
+                        jobs = new_jobs;
+                        reload(&mut children, processes, &jobs, &queue);
+                    })
+                };
+
+                if let Err(err) = res {
+                    log!("Config reload failed: {}", err);
+                }
+
+                // SIGHUP is forwarded only to the processes asking for it
+                for child in children.iter() {
+                    if child.config.has_signal_route(Signal::SIGHUP) {
+                        if let Some(sent) = child.forward_signal(Signal::SIGHUP) {
+                            metrics.lock().unwrap().count_signal(sent);
+                        }
+                    }
+                }
+            }
+
+            Ok(Message::ParentSignal(Signal::SIGHUP)) => {
+                log!("Forwarding parent signal {} to children", Signal::SIGHUP);
+                forward = Some(Signal::SIGHUP);
+            }
+
+            // Forwarded without the log line since SIGWINCH comes on every
+            // terminal resize
+            Ok(Message::ParentSignal(parent_signal)) if config::is_opt_in(parent_signal) => {
+                forward = Some(parent_signal);
+            }
+
             Ok(Message::ParentSignal(parent_signal)) => {
                 log!("Forwarding parent signal {} to children", parent_signal);
                 forward = Some(parent_signal);
+                if parent_signal == Signal::SIGTERM || parent_signal == Signal::SIGQUIT {
+                    exit_requested = true;
+                }
+            }
+
+            Ok(Message::Control(control::Request::Logs { names, follow }, reply)) => {
+                show_logs(
+                    &children,
+                    &mut history.lock().unwrap(),
+                    names,
+                    follow,
+                    reply,
+                );
+            }
+
+            Ok(Message::Control(request, reply)) => {
+                let shutting_down = killall.is_some() || exit_requested;
+                reply.send(handle_control(&mut children, request, shutting_down));
             }
 
-            Ok(Message::Line(line)) => {
-                line.print();
+            Ok(Message::Http(path, reply)) => {
+                reply.send(handle_http(&children, &metrics.lock().unwrap(), &path));
             }
 
             Err(RecvTimeoutError::Disconnected) => {
@@ -351,32 +1752,169 @@ fn main() {
             }
         }
 
+        let now = Instant::now();
+        for child in children.iter_mut() {
+            child.flush_expired_output(now);
+        }
+
+        let changed: Vec<(String, String)> = children
+            .iter_mut()
+            .filter_map(|child| {
+                let path = child.watcher.as_mut()?.take_changed(now)?;
+                Some((child.name().to_string(), path))
+            })
+            .collect();
+
+        if killall.is_none() && !exit_requested {
+            for (name, path) in changed {
+                restart_watched(&mut children, &name, &path);
+            }
+        }
+
+        // Report the dropped lines at most once a second
+        if last_dropped_notice.elapsed() >= Duration::from_secs(1) {
+            print_dropped(&queue, &metrics);
+            last_dropped_notice = Instant::now();
+        }
+
+        if killall.is_none() && !exit_requested {
+            let now = SystemTime::now();
+
+            for job in jobs.iter_mut() {
+                if job.next_run.is_some_and(|next_run| now >= next_run) {
+                    run_job(&mut children, job, &queue);
+                    job.next_run = job.schedule.next_after(now);
+                }
+            }
+        }
+
+        // Forget the processes removed from the config once they are stopped
+        children.retain(|child| {
+            let keep = !child.removed || child.is_alive();
+            if let (false, Some(cgroup)) = (keep, &child.cgroup) {
+                cgroup.remove();
+            }
+            keep
+        });
+
         for child in children.iter_mut() {
             if let Some(sig) = forward {
-                child.kill(sig);
+                if let Some(sent) = child.forward_signal(sig) {
+                    metrics.lock().unwrap().count_signal(sent);
+                }
             }
 
             if let Some(sig) = killall {
-                child.kill(sig);
+                child.kill(child.config.shutdown_signal(sig));
+            }
+
+            if let Some(deadline) = child.stop_deadline {
+                if child.is_process_alive() && Instant::now() >= deadline {
+                    log!("{} did not stop in time", child);
+                    child.kill(Signal::SIGKILL);
+                }
             }
         }
 
-        if !somebody_is_alive {
+        // Keep waiting for the start command when processes were stopped
+        // using the control socket
+        let stopped_by_control = killall.is_none()
+            && !exit_requested
+            && children.iter().any(|child| child.stop_requested);
+
+        // Stop the orphaned descendants once all children are gone
+        let mut strays_left = false;
+        if !stopped_by_control && !children.iter().any(|child| child.is_process_alive()) {
+            let strays = procfs::descendants(Pid::this());
+            strays_left = !strays.is_empty();
+
+            match stray_deadline {
+                None if strays_left => {
+                    for stray in strays.iter() {
+                        log!("Sending SIGTERM to stray process {}", stray);
+                        kill(stray.pid, Signal::SIGTERM).unwrap_or(());
+                    }
+                    stray_deadline = Some(Instant::now() + stray_timeout);
+                }
+                Some(deadline) if strays_left && Instant::now() >= deadline => {
+                    for stray in strays.iter() {
+                        if killed_strays.contains(&stray.pid) {
+                            continue;
+                        }
+
+                        log!("Killing stray process {} with SIGKILL", stray);
+                        kill(stray.pid, Signal::SIGKILL).unwrap_or(());
+                        killed_strays.push(stray.pid);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let somebody_is_alive = children.iter().any(|child| child.is_alive());
+
+        // Keep waiting for the next runs of the jobs
+        let jobs_pending = !jobs.is_empty() && killall.is_none() && !exit_requested;
+
+        if !somebody_is_alive && !stopped_by_control && !strays_left && !jobs_pending {
             log!("All processes died. Exiting...");
             break;
         }
+
+        // Sleep until the next event or deadline
+        let now = Instant::now();
+        // The passed deadlines have been handled already
+        let mut deadlines: Vec<Instant> = children
+            .iter()
+            .filter_map(|child| child.stop_deadline)
+            .chain(children.iter().filter_map(MultipChild::output_deadline))
+            .chain(
+                children
+                    .iter()
+                    .filter_map(|child| child.watcher.as_ref()?.deadline()),
+            )
+            .chain(stray_deadline)
+            .filter(|&deadline| deadline > now)
+            .collect();
+
+        if jobs_pending {
+            let system_now = SystemTime::now();
+            deadlines.extend(
+                jobs.iter()
+                    .filter_map(|job| job.next_run)
+                    .map(|next_run| now + next_run.duration_since(system_now).unwrap_or_default()),
+            );
+        }
+
+        if queue.has_dropped() {
+            deadlines.push((last_dropped_notice + Duration::from_secs(1)).max(now));
+        }
+
+        // The descendants of the strays are not reaped by multip so there is
+        // no event when they exit
+        if strays_left {
+            deadlines.push(now + Duration::from_millis(100));
+        }
+
+        for child in children.iter_mut() {
+            child.register(&events);
+        }
+
+        msg = events.next(deadlines.into_iter().min());
     }
 
     // Print all pending message from the buffers
-    for msg in rx.try_iter() {
-        match msg {
-            Message::Line(line) => {
-                line.print();
-            }
-            Message::ParentSignal(_) => {
-                // Ignore signals on exit
-            }
-        }
+    print_dropped(&queue, &metrics);
+    log::set_output(None);
+    queue.close();
+    printer.join().unwrap_or(());
+
+    if let Some(path) = control_socket {
+        std::fs::remove_file(path).unwrap_or(());
+    }
+
+    if let Some(http::Address::Unix(path)) = http_address {
+        std::fs::remove_file(path).unwrap_or(());
     }
 
     std::process::exit(multip_exit_code.unwrap_or(0));
@@ -0,0 +1,229 @@
+use nix::sys::signal::Signal;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::{line_reader, Line, MultipChild};
+
+/// Counters for the events seen by the main loop
+#[derive(Default)]
+pub struct Metrics {
+    /// Lines per process and stream
+    lines: BTreeMap<(String, &'static str), u64>,
+    partial_lines: BTreeMap<(String, &'static str), u64>,
+    dropped_lines: BTreeMap<String, u64>,
+    zombies_reaped: u64,
+    signals_forwarded: BTreeMap<String, u64>,
+}
+
+/// Escape a Prometheus label value
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn write_family(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+impl Metrics {
+    pub fn count_line(&mut self, line: &Line) {
+        let key = (line.name.clone(), line.stream.as_str());
+
+        if let Ok(line_reader::Line::PartialLine(_)) = line.line {
+            *self.partial_lines.entry(key.clone()).or_insert(0) += 1;
+        }
+
+        *self.lines.entry(key).or_insert(0) += 1;
+    }
+
+    pub fn count_dropped(&mut self, name: &str, count: usize) {
+        *self.dropped_lines.entry(name.to_string()).or_insert(0) += count as u64;
+    }
+
+    pub fn count_zombie(&mut self) {
+        self.zombies_reaped += 1;
+    }
+
+    pub fn count_signal(&mut self, sig: Signal) {
+        *self.signals_forwarded.entry(sig.to_string()).or_insert(0) += 1;
+    }
+
+    /// Prometheus text format
+    pub fn render(&self, children: &[MultipChild]) -> String {
+        let mut out = String::new();
+        let children: Vec<&MultipChild> = children.iter().filter(|c| !c.removed).collect();
+
+        write_family(
+            &mut out,
+            "multip_process_up",
+            "gauge",
+            "Is the process running",
+        );
+        for child in children.iter() {
+            writeln!(
+                out,
+                "multip_process_up{{process=\"{}\"}} {}",
+                escape_label(child.name()),
+                child.is_process_alive() as u8
+            )
+            .unwrap();
+        }
+
+        write_family(
+            &mut out,
+            "multip_process_restarts_total",
+            "counter",
+            "Times the process has been started again",
+        );
+        for child in children.iter() {
+            writeln!(
+                out,
+                "multip_process_restarts_total{{process=\"{}\"}} {}",
+                escape_label(child.name()),
+                child.restarts
+            )
+            .unwrap();
+        }
+
+        write_family(
+            &mut out,
+            "multip_process_last_exit_code",
+            "gauge",
+            "Exit code of the last exited process",
+        );
+        for child in children.iter() {
+            if let Some(code) = child.last_exit_code {
+                writeln!(
+                    out,
+                    "multip_process_last_exit_code{{process=\"{}\"}} {}",
+                    escape_label(child.name()),
+                    code
+                )
+                .unwrap();
+            }
+        }
+
+        write_family(
+            &mut out,
+            "multip_process_uptime_seconds",
+            "gauge",
+            "Seconds since the process was started, 0 when it is not running",
+        );
+        for child in children.iter() {
+            let uptime = if child.is_process_alive() {
+                child.started_at.elapsed().as_secs_f64()
+            } else {
+                0.0
+            };
+            writeln!(
+                out,
+                "multip_process_uptime_seconds{{process=\"{}\"}} {:.3}",
+                escape_label(child.name()),
+                uptime
+            )
+            .unwrap();
+        }
+
+        let line_families = [
+            (
+                "multip_lines_emitted_total",
+                "Lines printed from the process",
+                &self.lines,
+            ),
+            (
+                "multip_partial_lines_total",
+                "Lines split because they were too long or the stream was idle",
+                &self.partial_lines,
+            ),
+        ];
+
+        for (name, help, counts) in line_families.iter() {
+            write_family(&mut out, name, "counter", help);
+            for ((process, stream), count) in counts.iter() {
+                writeln!(
+                    out,
+                    "{}{{process=\"{}\",stream=\"{}\"}} {}",
+                    name,
+                    escape_label(process),
+                    stream,
+                    count
+                )
+                .unwrap();
+            }
+        }
+
+        write_family(
+            &mut out,
+            "multip_dropped_lines_total",
+            "counter",
+            "Lines dropped because the output buffer was full",
+        );
+        for (process, count) in self.dropped_lines.iter() {
+            writeln!(
+                out,
+                "multip_dropped_lines_total{{process=\"{}\"}} {}",
+                escape_label(process),
+                count
+            )
+            .unwrap();
+        }
+
+        write_family(
+            &mut out,
+            "multip_zombies_reaped_total",
+            "counter",
+            "Orphaned processes reaped by multip",
+        );
+        writeln!(out, "multip_zombies_reaped_total {}", self.zombies_reaped).unwrap();
+
+        write_family(
+            &mut out,
+            "multip_signals_forwarded_total",
+            "counter",
+            "Signals forwarded to the processes",
+        );
+        for (sig, count) in self.signals_forwarded.iter() {
+            writeln!(
+                out,
+                "multip_signals_forwarded_total{{signal=\"{}\"}} {}",
+                sig, count
+            )
+            .unwrap();
+        }
+
+        out
+    }
+}
+
+#[test]
+fn escapes_labels() {
+    assert_eq!(escape_label(r#"a "b" \c"#), r#"a \"b\" \\c"#);
+    assert_eq!(escape_label("a\nb"), "a\\nb");
+}
+
+#[test]
+fn renders_counters() {
+    let mut metrics = Metrics::default();
+
+    metrics.count_line(&crate::Line {
+        name: String::from("web"),
+        pid: 1,
+        stream: crate::Stream::Stderr,
+        line: Ok(line_reader::Line::PartialLine(String::from("abc"))),
+    });
+    metrics.count_dropped("web", 3);
+    metrics.count_zombie();
+    metrics.count_signal(Signal::SIGTERM);
+    metrics.count_signal(Signal::SIGTERM);
+
+    let out = metrics.render(&[]);
+
+    assert!(out.contains("\nmultip_lines_emitted_total{process=\"web\",stream=\"stderr\"} 1\n"));
+    assert!(out.contains("\nmultip_partial_lines_total{process=\"web\",stream=\"stderr\"} 1\n"));
+    assert!(out.contains("\nmultip_dropped_lines_total{process=\"web\"} 3\n"));
+    assert!(out.contains("\nmultip_zombies_reaped_total 1\n"));
+    assert!(out.contains("\nmultip_signals_forwarded_total{signal=\"SIGTERM\"} 2\n"));
+}
@@ -0,0 +1,238 @@
+use regex::Regex;
+use std::io::Error;
+use std::time::{Duration, Instant};
+
+use crate::config::MultilineConfig;
+use crate::line_reader::Line;
+
+/// Joins continuation lines (stack traces etc.) with the preceding line so
+/// they are emitted as a single event
+#[derive(Clone)]
+pub struct MultilineGrouper {
+    continuation: Regex,
+    separator: String,
+    flush_timeout: Duration,
+    max_lines: usize,
+    pending: Option<String>,
+    pending_lines: usize,
+    pending_since: Instant,
+}
+
+fn strip_line_ending(s: &str) -> &str {
+    s.trim_end_matches(['\n', '\r'])
+}
+
+impl MultilineGrouper {
+    pub fn new(name: &str, config: &MultilineConfig) -> Result<MultilineGrouper, String> {
+        let continuation = Regex::new(&config.pattern)
+            .map_err(|err| format!("Invalid multiline pattern for [{}]: {}", name, err))?;
+
+        Ok(MultilineGrouper {
+            continuation,
+            separator: config.separator.clone(),
+            flush_timeout: Duration::from_millis(config.flush_timeout_ms),
+            max_lines: config.max_lines,
+            pending: None,
+            pending_lines: 0,
+            pending_since: Instant::now(),
+        })
+    }
+
+    fn is_continuation(&self, s: &str) -> bool {
+        self.pending.is_some()
+            && self.pending_lines < self.max_lines
+            && self.continuation.is_match(s)
+    }
+
+    fn append(&mut self, s: &str) {
+        if let Some(pending) = self.pending.as_mut() {
+            pending.push_str(&self.separator);
+            pending.push_str(s);
+            self.pending_lines += 1;
+        }
+    }
+
+    /// Emit the pending event if any
+    pub fn flush(&mut self) -> Option<Line> {
+        self.pending_lines = 0;
+        self.pending.take().map(|mut s| {
+            s.push('\n');
+            Line::FullLine(s)
+        })
+    }
+
+    /// How long to wait for more continuation lines before the pending event
+    /// must be flushed
+    pub fn time_left(&self) -> Option<Duration> {
+        self.pending.as_ref()?;
+        Some(
+            self.flush_timeout
+                .checked_sub(self.pending_since.elapsed())
+                .unwrap_or_default(),
+        )
+    }
+
+    /// Returns the lines that are ready to be emitted
+    pub fn push(&mut self, line: Line) -> Vec<Line> {
+        let mut ready = Vec::new();
+
+        match line {
+            Line::FullLine(s) => {
+                let s = strip_line_ending(&s);
+
+                if self.is_continuation(s) {
+                    self.append(s);
+                } else {
+                    ready.extend(self.flush());
+                    self.pending = Some(s.to_string());
+                    self.pending_lines = 1;
+                    self.pending_since = Instant::now();
+                }
+            }
+
+            // Split long lines and raw lines are not grouped
+            line @ Line::PartialLine(_) | line @ Line::Raw(_) => {
+                ready.extend(self.flush());
+                ready.push(line);
+            }
+
+            Line::EOF(s) => {
+                if self.is_continuation(strip_line_ending(&s)) {
+                    self.append(strip_line_ending(&s));
+                    ready.extend(self.flush());
+                    ready.push(Line::EOF(String::new()));
+                } else {
+                    ready.extend(self.flush());
+                    ready.push(Line::EOF(s));
+                }
+            }
+        }
+
+        ready
+    }
+}
+
+/// Pass a line read from the stream through the grouper. Returns the lines
+/// ready to be emitted.
+pub fn group_line(
+    grouper: &mut MultilineGrouper,
+    line: Result<Line, Error>,
+) -> Vec<Result<Line, Error>> {
+    match line {
+        Ok(line) => grouper.push(line).into_iter().map(Ok).collect(),
+        Err(err) => grouper
+            .flush()
+            .map(Ok)
+            .into_iter()
+            .chain(Some(Err(err)))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+fn grouper(pattern: &str) -> MultilineGrouper {
+    MultilineGrouper::new(
+        "test",
+        &MultilineConfig {
+            pattern: pattern.to_string(),
+            ..Default::default()
+        },
+    )
+    .unwrap()
+}
+
+#[cfg(test)]
+fn full(s: &str) -> Line {
+    Line::FullLine(s.to_string())
+}
+
+#[cfg(test)]
+fn lines_to_strings(lines: Vec<Line>) -> Vec<String> {
+    lines.iter().map(|line| format!("{}", line)).collect()
+}
+
+#[test]
+fn joins_indented_lines() {
+    let mut grouper = grouper(r"^\s");
+
+    assert!(grouper.push(full("Exception in thread main\n")).is_empty());
+    assert!(grouper.push(full("\tat Foo.bar(Foo.java:1)\n")).is_empty());
+    assert!(grouper.push(full("\tat Foo.main(Foo.java:2)\n")).is_empty());
+
+    let ready = lines_to_strings(grouper.push(full("next\n")));
+    assert_eq!(
+        ready,
+        vec!["FullLine(Exception in thread main\n\tat Foo.bar(Foo.java:1)\n\tat Foo.main(Foo.java:2)\n)"]
+    );
+
+    let ready = lines_to_strings(grouper.flush().into_iter().collect());
+    assert_eq!(ready, vec!["FullLine(next\n)"]);
+}
+
+#[test]
+fn continuation_without_preceding_line_starts_an_event() {
+    let mut grouper = grouper(r"^\s");
+
+    assert!(grouper.push(full("  indented\n")).is_empty());
+    let ready = lines_to_strings(grouper.flush().into_iter().collect());
+    assert_eq!(ready, vec!["FullLine(  indented\n)"]);
+}
+
+#[test]
+fn custom_separator() {
+    let mut grouper = MultilineGrouper::new(
+        "test",
+        &MultilineConfig {
+            pattern: String::from("^Caused by"),
+            separator: String::from(" | "),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    grouper.push(full("Error\n"));
+    grouper.push(full("Caused by: other\n"));
+
+    let ready = lines_to_strings(grouper.flush().into_iter().collect());
+    assert_eq!(ready, vec!["FullLine(Error | Caused by: other\n)"]);
+}
+
+#[test]
+fn limits_event_size() {
+    let mut grouper = MultilineGrouper::new(
+        "test",
+        &MultilineConfig {
+            max_lines: 2,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    grouper.push(full("a\n"));
+    grouper.push(full(" b\n"));
+
+    let ready = lines_to_strings(grouper.push(full(" c\n")));
+    assert_eq!(ready, vec!["FullLine(a\n b\n)"]);
+}
+
+#[test]
+fn flushes_before_partial_and_eof() {
+    let mut grouper = grouper(r"^\s");
+
+    grouper.push(full("a\n"));
+    let ready = lines_to_strings(grouper.push(Line::PartialLine(String::from("long"))));
+    assert_eq!(ready, vec!["FullLine(a\n)", "PartialLine(long)"]);
+
+    grouper.push(full("b\n"));
+    let ready = lines_to_strings(grouper.push(Line::EOF(String::from(" c"))));
+    assert_eq!(ready, vec!["FullLine(b\n c\n)", "EOF()"]);
+}
+
+#[test]
+fn has_no_deadline_without_pending_event() {
+    let mut grouper = grouper(r"^\s");
+    assert!(grouper.time_left().is_none());
+
+    grouper.push(full("a\n"));
+    assert!(grouper.time_left().is_some());
+}
@@ -0,0 +1,238 @@
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+use crate::config::BufferFull;
+use crate::{Line, Stream};
+
+/// Output waiting to be written by the printer thread
+pub enum Entry {
+    Line(Line),
+    /// Message of multip itself
+    Log(String),
+    /// Output of a process with `passthrough = "copy"`
+    Copy(Stream, Vec<u8>),
+}
+
+struct State {
+    entries: VecDeque<Entry>,
+    /// Dropped line counts per process since the last notice
+    dropped: Vec<(String, usize)>,
+    /// Set when multip is exiting. The printer stops once the queue is empty.
+    closed: bool,
+}
+
+impl State {
+    fn count_dropped(&mut self, name: &str) {
+        match self.dropped.iter_mut().find(|(n, _)| n == name) {
+            Some((_, count)) => *count += 1,
+            None => self.dropped.push((name.to_string(), 1)),
+        }
+    }
+}
+
+/// Bounded buffer between the main loop reading the output of the processes
+/// and the printer thread. The main loop never waits for the printer: it
+/// stops reading the processes with `BufferFull::Block` while the queue is
+/// full.
+pub struct OutputQueue {
+    state: Mutex<State>,
+    not_empty: Condvar,
+    capacity: usize,
+}
+
+impl OutputQueue {
+    pub fn new(capacity: usize) -> OutputQueue {
+        OutputQueue {
+            state: Mutex::new(State {
+                entries: VecDeque::new(),
+                dropped: Vec::new(),
+                closed: false,
+            }),
+            not_empty: Condvar::new(),
+            capacity: capacity.max(1),
+        }
+    }
+
+    fn push_entry(&self, mut state: MutexGuard<'_, State>, entry: Entry) {
+        state.entries.push_back(entry);
+        drop(state);
+        self.not_empty.notify_one();
+    }
+
+    /// Queue the line using the given policy when the queue is full. Lines
+    /// with `BufferFull::Block` are always queued since the reading stops
+    /// once the queue is full.
+    pub fn push(&self, line: Line, policy: BufferFull) {
+        let mut state = self.state.lock().unwrap();
+
+        if state.entries.len() >= self.capacity {
+            match policy {
+                BufferFull::Block => {}
+                BufferFull::DropNewest => {
+                    state.count_dropped(&line.name);
+                    return;
+                }
+                BufferFull::DropOldest => {
+                    // Drop only the lines from the same process so the
+                    // processes using other policies are not affected
+                    let oldest = state
+                        .entries
+                        .iter()
+                        .position(|e| matches!(e, Entry::Line(l) if l.name == line.name));
+
+                    state.count_dropped(&line.name);
+                    match oldest {
+                        Some(i) => {
+                            state.entries.remove(i);
+                        }
+                        None => return,
+                    }
+                }
+            }
+        }
+
+        self.push_entry(state, Entry::Line(line));
+    }
+
+    /// Log messages are never dropped
+    pub fn push_log(&self, text: String) {
+        let state = self.state.lock().unwrap();
+        self.push_entry(state, Entry::Log(text));
+    }
+
+    pub fn push_copy(&self, stream: Stream, bytes: Vec<u8>) {
+        let state = self.state.lock().unwrap();
+        self.push_entry(state, Entry::Copy(stream, bytes));
+    }
+
+    /// Reading the processes using `BufferFull::Block` must wait
+    pub fn is_full(&self) -> bool {
+        self.state.lock().unwrap().entries.len() >= self.capacity
+    }
+
+    /// Wait for the queued entries and take them all. Also tells whether the
+    /// queue was full. Returns None once the queue is closed and empty.
+    pub fn wait_take(&self) -> Option<(VecDeque<Entry>, bool)> {
+        let mut state = self.state.lock().unwrap();
+
+        while state.entries.is_empty() {
+            if state.closed {
+                return None;
+            }
+            state = self.not_empty.wait(state).unwrap();
+        }
+
+        let was_full = state.entries.len() >= self.capacity;
+        Some((std::mem::take(&mut state.entries), was_full))
+    }
+
+    /// Let the printer stop after the entries queued so far
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.not_empty.notify_all();
+    }
+
+    pub fn has_dropped(&self) -> bool {
+        !self.state.lock().unwrap().dropped.is_empty()
+    }
+
+    pub fn take_dropped(&self) -> Vec<(String, usize)> {
+        let mut state = self.state.lock().unwrap();
+        std::mem::take(&mut state.dropped)
+    }
+}
+
+#[cfg(test)]
+fn test_line(name: &str, s: &str) -> Line {
+    Line {
+        name: name.to_string(),
+        pid: 1,
+        stream: crate::Stream::Stdout,
+        line: Ok(crate::line_reader::Line::FullLine(s.to_string())),
+    }
+}
+
+#[cfg(test)]
+fn queued(queue: &OutputQueue) -> Vec<String> {
+    let (entries, _) = queue.wait_take().unwrap();
+    entries
+        .iter()
+        .map(|entry| match entry {
+            Entry::Line(line) => line.to_string(),
+            Entry::Log(text) => text.clone(),
+            Entry::Copy(_, bytes) => String::from_utf8_lossy(bytes).into_owned(),
+        })
+        .collect()
+}
+
+#[test]
+fn keeps_lines_with_block_policy_when_full() {
+    let queue = OutputQueue::new(1);
+
+    queue.push(test_line("a", "1"), BufferFull::Block);
+    assert!(queue.is_full());
+    queue.push(test_line("a", "2"), BufferFull::Block);
+
+    assert_eq!(queue.wait_take().map(|(_, was_full)| was_full), Some(true));
+    assert!(!queue.is_full());
+}
+
+#[test]
+fn drops_newest_when_full() {
+    let queue = OutputQueue::new(2);
+
+    queue.push(test_line("a", "1"), BufferFull::DropNewest);
+    queue.push(test_line("a", "2"), BufferFull::DropNewest);
+    queue.push(test_line("a", "3"), BufferFull::DropNewest);
+
+    assert_eq!(queued(&queue), vec!["[a] 1\n", "[a] 2\n"]);
+    assert_eq!(queue.take_dropped(), vec![(String::from("a"), 1)]);
+    assert!(queue.take_dropped().is_empty());
+}
+
+#[test]
+fn drops_oldest_line_of_the_same_process() {
+    let queue = OutputQueue::new(2);
+
+    queue.push(test_line("b", "1"), BufferFull::Block);
+    queue.push(test_line("a", "2"), BufferFull::DropOldest);
+    queue.push(test_line("a", "3"), BufferFull::DropOldest);
+
+    assert_eq!(queued(&queue), vec!["[b] 1\n", "[a] 3\n"]);
+    assert_eq!(queue.take_dropped(), vec![(String::from("a"), 1)]);
+}
+
+#[test]
+fn never_drops_log_messages() {
+    let queue = OutputQueue::new(1);
+
+    queue.push(test_line("a", "1"), BufferFull::DropNewest);
+    queue.push_log(String::from("log"));
+
+    assert_eq!(queued(&queue), vec!["[a] 1\n", "log"]);
+}
+
+#[test]
+fn waits_for_entries_until_closed() {
+    use std::sync::Arc;
+    use std::thread;
+
+    let queue = Arc::new(OutputQueue::new(10));
+
+    let printer = {
+        let queue = Arc::clone(&queue);
+        thread::spawn(move || {
+            let mut count = 0;
+            while let Some((entries, _)) = queue.wait_take() {
+                count += entries.len();
+            }
+            count
+        })
+    };
+
+    queue.push(test_line("a", "1"), BufferFull::Block);
+    queue.push(test_line("a", "2"), BufferFull::Block);
+    queue.close();
+
+    assert_eq!(printer.join().unwrap(), 2);
+}
@@ -0,0 +1,105 @@
+use nix::errno::Errno;
+use nix::sys::signal::Signal;
+use nix::unistd::{close, Pid};
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use crate::debug;
+
+// Not in the libc crate version we use. The numbers are the same on all
+// architectures.
+#[cfg(target_os = "linux")]
+const SYS_PIDFD_SEND_SIGNAL: libc::c_long = 424;
+#[cfg(target_os = "linux")]
+const SYS_PIDFD_OPEN: libc::c_long = 434;
+
+/// File descriptor referring to a process. Unlike the numeric pid it cannot
+/// point to another process after the original one has been reaped.
+pub struct PidFd(RawFd);
+
+impl PidFd {
+    /// Returns None when the kernel does not support pidfds (before Linux
+    /// 5.3) or they are blocked by seccomp
+    #[cfg(target_os = "linux")]
+    pub fn open(pid: Pid) -> Option<PidFd> {
+        let ret = unsafe { libc::syscall(SYS_PIDFD_OPEN, pid.as_raw(), 0) };
+
+        if ret < 0 {
+            debug!("pidfd_open() failed for {}: {}", pid, Errno::last());
+            return None;
+        }
+
+        Some(PidFd(ret as RawFd))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn open(_pid: Pid) -> Option<PidFd> {
+        None
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn send_signal(&self, sig: Signal) -> nix::Result<()> {
+        let ret = unsafe {
+            libc::syscall(
+                SYS_PIDFD_SEND_SIGNAL,
+                self.0,
+                sig as libc::c_int,
+                std::ptr::null::<libc::siginfo_t>(),
+                0,
+            )
+        };
+
+        Errno::result(ret).map(drop)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn send_signal(&self, _sig: Signal) -> nix::Result<()> {
+        Err(nix::Error::Sys(Errno::ENOSYS))
+    }
+}
+
+/// Readable once the process has exited
+impl AsRawFd for PidFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl Drop for PidFd {
+    fn drop(&mut self) {
+        close(self.0).unwrap_or(());
+    }
+}
+
+#[test]
+fn detects_exit() {
+    let mut child = std::process::Command::new("sleep")
+        .arg("10")
+        .spawn()
+        .unwrap();
+
+    let pidfd = PidFd::open(Pid::from_raw(child.id() as i32));
+
+    // Not supported by the kernel
+    if pidfd.is_none() {
+        child.kill().unwrap();
+    }
+
+    if let Some(pidfd) = &pidfd {
+        use nix::poll::{poll, PollFd, PollFlags};
+
+        pidfd.send_signal(Signal::SIGKILL).unwrap();
+
+        let mut fds = [PollFd::new(pidfd.as_raw_fd(), PollFlags::POLLIN)];
+        assert_eq!(poll(&mut fds, 5000), Ok(1));
+    }
+
+    child.wait().unwrap();
+
+    // Reaped processes cannot be signaled even if the pid is reused
+    if let Some(pidfd) = &pidfd {
+        assert_eq!(
+            pidfd.send_signal(Signal::SIGKILL),
+            Err(nix::Error::Sys(Errno::ESRCH))
+        );
+    }
+}
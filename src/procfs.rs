@@ -0,0 +1,158 @@
+use nix::unistd::Pid;
+use std::fmt;
+use std::fs;
+use std::time::Duration;
+
+use crate::rusage::ResourceUsage;
+
+/// Process found from /proc
+pub struct ProcessInfo {
+    pub pid: Pid,
+    pub ppid: Pid,
+    pub name: String,
+    pub state: char,
+}
+
+impl fmt::Display for ProcessInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}({})", self.name, self.pid)
+    }
+}
+
+/// Parse /proc/[pid]/stat. The name is in parentheses and may contain spaces
+/// and parentheses itself so the fields are read after the last `)`.
+fn parse_stat(stat: &str) -> Option<ProcessInfo> {
+    let open = stat.find('(')?;
+    let close = stat.rfind(')')?;
+    let pid = stat[..open].trim().parse().ok()?;
+    let name = stat.get(open + 1..close)?.to_string();
+
+    let mut fields = stat[close + 1..].split_whitespace();
+    let state = fields.next()?.chars().next()?;
+    let ppid = fields.next()?.parse().ok()?;
+
+    Some(ProcessInfo {
+        pid: Pid::from_raw(pid),
+        ppid: Pid::from_raw(ppid),
+        name,
+        state,
+    })
+}
+
+/// Parse the usage of a running process from /proc/[pid]/stat and
+/// /proc/[pid]/status. The times include the children the process has waited
+/// for like the rusage from wait4() does.
+fn parse_usage(stat: &str, status: &str, ticks_per_sec: u64) -> Option<ResourceUsage> {
+    // utime, stime, cutime and cstime are the fields 14-17 and the fields
+    // after the name start from the third one
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+    let ticks = |i: usize| -> Option<u64> { fields.get(i - 3)?.parse().ok() };
+    let to_duration = |ticks: u64| Duration::from_millis(ticks * 1000 / ticks_per_sec.max(1));
+
+    let status_value = |key: &str| -> u64 {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(key))
+            .and_then(|value| value.trim().trim_end_matches(" kB").parse().ok())
+            .unwrap_or(0)
+    };
+
+    Some(ResourceUsage {
+        user_time: to_duration(ticks(14)? + ticks(16)?),
+        system_time: to_duration(ticks(15)? + ticks(17)?),
+        max_rss_kb: status_value("VmHWM:"),
+        voluntary_switches: status_value("voluntary_ctxt_switches:"),
+        involuntary_switches: status_value("nonvoluntary_ctxt_switches:"),
+    })
+}
+
+/// Resource usage of a running process
+pub fn usage(pid: Pid) -> Option<ResourceUsage> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    let ticks_per_sec = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+
+    parse_usage(&stat, &status, ticks_per_sec as u64)
+}
+
+fn list_processes() -> Vec<ProcessInfo> {
+    let entries = match fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().parse::<i32>().is_ok())
+        // The process may have exited already
+        .filter_map(|entry| fs::read_to_string(entry.path().join("stat")).ok())
+        .filter_map(|stat| parse_stat(&stat))
+        .collect()
+}
+
+/// Living descendants of the given process. Zombies are skipped as they are
+/// waiting only to be reaped.
+pub fn descendants(pid: Pid) -> Vec<ProcessInfo> {
+    let mut processes = list_processes();
+    let mut parents = vec![pid];
+    let mut found = Vec::new();
+
+    while let Some(parent) = parents.pop() {
+        let (children, rest) = processes
+            .into_iter()
+            .partition(|process: &ProcessInfo| process.ppid == parent);
+        processes = rest;
+
+        for child in children {
+            parents.push(child.pid);
+            if child.state != 'Z' {
+                found.push(child);
+            }
+        }
+    }
+
+    found
+}
+
+#[test]
+fn parses_stat() {
+    let info = parse_stat("1234 (my (weird) name) S 42 1234 1234 0 -1 4194560").unwrap();
+
+    assert_eq!(info.pid, Pid::from_raw(1234));
+    assert_eq!(info.ppid, Pid::from_raw(42));
+    assert_eq!(info.name, "my (weird) name");
+    assert_eq!(info.state, 'S');
+}
+
+#[test]
+fn parses_usage() {
+    let stat = "1234 (my name) S 42 1234 1234 0 -1 4194560 100 0 0 0 150 25 50 25 20 0 1";
+    let status = "Name:\tmy name\nVmHWM:\t    2048 kB\nvoluntary_ctxt_switches:\t10\nnonvoluntary_ctxt_switches:\t2\n";
+
+    assert_eq!(
+        parse_usage(stat, status, 100),
+        Some(ResourceUsage {
+            user_time: Duration::from_secs(2),
+            system_time: Duration::from_millis(500),
+            max_rss_kb: 2048,
+            voluntary_switches: 10,
+            involuntary_switches: 2,
+        })
+    );
+}
+
+#[test]
+fn finds_descendants() {
+    let mut child = std::process::Command::new("sleep")
+        .arg("10")
+        .spawn()
+        .unwrap();
+
+    let found = descendants(Pid::this());
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    assert!(found
+        .iter()
+        .any(|process| process.pid == Pid::from_raw(child.id() as i32)));
+}
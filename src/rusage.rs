@@ -0,0 +1,82 @@
+use std::fmt;
+use std::time::Duration;
+
+/// CPU time, memory and context switches used by a process
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ResourceUsage {
+    pub user_time: Duration,
+    pub system_time: Duration,
+    /// Peak resident set size in KiB
+    pub max_rss_kb: u64,
+    pub voluntary_switches: u64,
+    pub involuntary_switches: u64,
+}
+
+fn timeval_to_duration(tv: libc::timeval) -> Duration {
+    Duration::from_secs(tv.tv_sec as u64) + Duration::from_micros(tv.tv_usec as u64)
+}
+
+impl ResourceUsage {
+    pub fn from_rusage(usage: &libc::rusage) -> ResourceUsage {
+        ResourceUsage {
+            user_time: timeval_to_duration(usage.ru_utime),
+            system_time: timeval_to_duration(usage.ru_stime),
+            max_rss_kb: usage.ru_maxrss as u64,
+            voluntary_switches: usage.ru_nvcsw as u64,
+            involuntary_switches: usage.ru_nivcsw as u64,
+        }
+    }
+
+    pub fn cpu_time(&self) -> Duration {
+        self.user_time + self.system_time
+    }
+
+    /// CPU time for the status table
+    pub fn format_cpu(&self) -> String {
+        format!("{:.2}s", self.cpu_time().as_secs_f64())
+    }
+
+    /// Peak memory for the status table
+    pub fn format_max_rss(&self) -> String {
+        if self.max_rss_kb >= 1024 {
+            format!("{:.1}M", self.max_rss_kb as f64 / 1024.0)
+        } else {
+            format!("{}K", self.max_rss_kb)
+        }
+    }
+}
+
+impl fmt::Display for ResourceUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "cpu {:.2}s (user {:.2}s, system {:.2}s), max rss {} KiB, \
+             context switches {} voluntary {} involuntary",
+            self.cpu_time().as_secs_f64(),
+            self.user_time.as_secs_f64(),
+            self.system_time.as_secs_f64(),
+            self.max_rss_kb,
+            self.voluntary_switches,
+            self.involuntary_switches
+        )
+    }
+}
+
+#[test]
+fn formats_usage() {
+    let usage = ResourceUsage {
+        user_time: Duration::from_millis(1500),
+        system_time: Duration::from_millis(250),
+        max_rss_kb: 2048,
+        voluntary_switches: 10,
+        involuntary_switches: 2,
+    };
+
+    assert_eq!(usage.format_cpu(), "1.75s");
+    assert_eq!(usage.format_max_rss(), "2.0M");
+    assert_eq!(
+        usage.to_string(),
+        "cpu 1.75s (user 1.50s, system 0.25s), max rss 2048 KiB, \
+         context switches 10 voluntary 2 involuntary"
+    );
+}
@@ -1,32 +1,49 @@
 use nix::errno::Errno;
 use nix::sys::wait::WaitStatus::{Exited, Signaled, StillAlive};
-use nix::sys::wait::{waitpid, WaitPidFlag};
+use nix::sys::wait::{WaitPidFlag, WaitStatus};
 use nix::unistd::Pid;
 use nix::Error::Sys;
 
+use crate::rusage::ResourceUsage;
 use crate::*;
 
+/// waitpid() which also returns the resource usage of the process
+fn wait4(pid: Pid, options: WaitPidFlag) -> nix::Result<(WaitStatus, ResourceUsage)> {
+    let mut status: libc::c_int = 0;
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+
+    let res = unsafe { libc::wait4(pid.as_raw(), &mut status, options.bits(), &mut usage) };
+
+    match nix::errno::Errno::result(res)? {
+        0 => Ok((StillAlive, ResourceUsage::default())),
+        res => Ok((
+            WaitStatus::from_raw(Pid::from_raw(res), status)?,
+            ResourceUsage::from_rusage(&usage),
+        )),
+    }
+}
+
 pub struct ProcessWaiter {}
 
 impl Iterator for ProcessWaiter {
-    type Item = (Pid, i32);
+    type Item = (Pid, i32, ResourceUsage);
 
     fn next(&mut self) -> Option<Self::Item> {
         // -1     meaning wait for any child process.
         // WNOHANG     return immediately if no child has exited.
-        let status = waitpid(Pid::from_raw(-1), Some(WaitPidFlag::WNOHANG));
+        let status = wait4(Pid::from_raw(-1), WaitPidFlag::WNOHANG);
 
         match status {
-            Ok(Exited(pid, exit_code)) => Some((pid, exit_code)),
+            Ok((Exited(pid, exit_code), usage)) => Some((pid, exit_code, usage)),
 
-            Ok(Signaled(pid, signal, _core_dumped)) => {
+            Ok((Signaled(pid, signal, _core_dumped), usage)) => {
                 debug!("waitpid(): {} killed with signal {}", pid, signal);
-                Some((pid, 0))
+                Some((pid, 0, usage))
             }
 
-            Ok(StillAlive) => None,
+            Ok((StillAlive, _)) => None,
 
-            Ok(status) => {
+            Ok((status, _)) => {
                 log!("Unknown status from waitpid() {:#?}", status);
                 None
             }
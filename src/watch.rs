@@ -0,0 +1,318 @@
+use nix::errno::Errno;
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, InotifyEvent, WatchDescriptor};
+use nix::unistd::close;
+use regex::Regex;
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::config::WatchConfig;
+use crate::log;
+
+/// Convert a glob pattern to a regex. `*` and `?` do not match `/` and `**`
+/// matches any number of directories.
+fn glob_to_regex(glob: &str) -> Regex {
+    let glob = glob.strip_prefix("./").unwrap_or(glob);
+    let mut re = String::from("^");
+    let mut chars = glob.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    re.push_str("(?:.*/)?");
+                } else {
+                    re.push_str(".*");
+                }
+            }
+            '*' => re.push_str("[^/]*"),
+            '?' => re.push_str("[^/]"),
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+
+    re.push('$');
+    Regex::new(&re).expect("glob regex is escaped")
+}
+
+/// The directory to watch for the glob: the part before the first component
+/// with wildcards or the parent directory of a plain file path. Also returns
+/// how many levels of subdirectories the rest of the glob can match, or None
+/// for any depth below a `**`.
+fn glob_root(glob: &str) -> (PathBuf, Option<usize>) {
+    let mut root = PathBuf::new();
+    let components: Vec<&str> = glob.split('/').collect();
+
+    if glob.starts_with('/') {
+        root.push("/");
+    }
+
+    // The last component is the file name
+    let literal = components[..components.len() - 1]
+        .iter()
+        .take_while(|component| !component.contains(['*', '?']))
+        .count();
+
+    for component in components[..literal].iter() {
+        root.push(component);
+    }
+
+    let rest = &components[literal..];
+    let depth = if rest.iter().any(|component| component.contains("**")) {
+        None
+    } else {
+        Some(rest.len() - 1)
+    };
+
+    if root.as_os_str().is_empty() {
+        root.push(".");
+    }
+
+    (root, depth)
+}
+
+/// The path as matched against the patterns
+fn path_to_match(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    path.strip_prefix("./").unwrap_or(&path).to_string()
+}
+
+struct WatchState {
+    name: String,
+    inotify: Inotify,
+    /// inotify does not watch the subdirectories so each one is watched
+    /// separately. Also has the levels of subdirectories to watch below it.
+    dirs: HashMap<WatchDescriptor, (PathBuf, Option<usize>)>,
+    paths: Vec<Regex>,
+    ignore: Vec<Regex>,
+}
+
+impl WatchState {
+    fn is_ignored(&self, path: &str) -> bool {
+        self.ignore.iter().any(|re| re.is_match(path))
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        self.paths.iter().any(|re| re.is_match(path)) && !self.is_ignored(path)
+    }
+
+    /// Watch the directory and its subdirectories up to the given depth
+    fn add_tree(&mut self, dir: &Path, depth: Option<usize>) {
+        // Skip the ignored directories such as `node_modules/**`
+        if self.is_ignored(&format!("{}/", path_to_match(dir))) {
+            return;
+        }
+
+        let flags = AddWatchFlags::IN_CREATE
+            | AddWatchFlags::IN_MODIFY
+            | AddWatchFlags::IN_CLOSE_WRITE
+            | AddWatchFlags::IN_DELETE
+            | AddWatchFlags::IN_MOVED_FROM
+            | AddWatchFlags::IN_MOVED_TO
+            | AddWatchFlags::IN_ONLYDIR;
+
+        match self.inotify.add_watch(dir, flags) {
+            Ok(wd) => {
+                // The same directory may be watched for several globs
+                let depth = match self.dirs.get(&wd) {
+                    Some((_, None)) => None,
+                    Some((_, Some(watched))) => depth.map(|depth| depth.max(*watched)),
+                    None => depth,
+                };
+                self.dirs.insert(wd, (dir.to_path_buf(), depth));
+            }
+            Err(err) => {
+                log!(
+                    "Cannot watch {} for [{}]: {}",
+                    dir.display(),
+                    self.name,
+                    err
+                );
+                return;
+            }
+        }
+
+        let subdir_depth = match depth {
+            Some(0) => return,
+            Some(depth) => Some(depth - 1),
+            None => None,
+        };
+
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                self.add_tree(&entry.path(), subdir_depth);
+            }
+        }
+    }
+
+    /// Returns the changed path if it matches the patterns
+    fn handle_event(&mut self, event: InotifyEvent) -> Option<String> {
+        if event.mask.contains(AddWatchFlags::IN_IGNORED) {
+            self.dirs.remove(&event.wd);
+            return None;
+        }
+
+        let (dir, depth) = self.dirs.get(&event.wd)?;
+        let path = dir.join(event.name?);
+        let depth = *depth;
+
+        if event.mask.contains(AddWatchFlags::IN_ISDIR)
+            && event
+                .mask
+                .intersects(AddWatchFlags::IN_CREATE | AddWatchFlags::IN_MOVED_TO)
+            && depth != Some(0)
+        {
+            self.add_tree(&path, depth.map(|depth| depth - 1));
+        }
+
+        let path = path_to_match(&path);
+        if self.matches(&path) {
+            Some(path)
+        } else {
+            None
+        }
+    }
+}
+
+/// Watches the files of a process. The inotify fd is read by the main loop
+/// and closed when dropped.
+pub struct Watcher {
+    state: WatchState,
+    debounce: Duration,
+    /// The first changed path and the time to report it
+    changed: Option<(String, Instant)>,
+    pub registered: bool,
+}
+
+impl Watcher {
+    /// Read the pending file events
+    pub fn read_events(&mut self) {
+        loop {
+            match self.state.inotify.read_events() {
+                Ok(events) => {
+                    for event in events {
+                        if let Some(path) = self.state.handle_event(event) {
+                            let path = self.changed.take().map_or(path, |(first, _)| first);
+                            self.changed = Some((path, Instant::now() + self.debounce));
+                        }
+                    }
+                }
+                Err(nix::Error::Sys(Errno::EAGAIN)) => return,
+                Err(err) => {
+                    log!(
+                        "Failed to read file events for [{}]: {}",
+                        self.state.name,
+                        err
+                    );
+                    return;
+                }
+            }
+        }
+    }
+
+    /// When the changes have settled for the debounce time
+    pub fn deadline(&self) -> Option<Instant> {
+        self.changed.as_ref().map(|(_, deadline)| *deadline)
+    }
+
+    /// The changed path once the changes have settled
+    pub fn take_changed(&mut self, now: Instant) -> Option<String> {
+        match &self.changed {
+            Some((_, deadline)) if *deadline <= now => self.changed.take().map(|(path, _)| path),
+            _ => None,
+        }
+    }
+}
+
+impl AsRawFd for Watcher {
+    fn as_raw_fd(&self) -> RawFd {
+        self.state.inotify.as_raw_fd()
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        // Also removes the fd from the epoll set
+        close(self.as_raw_fd()).unwrap_or(());
+    }
+}
+
+/// Watch the files of the process
+pub fn watch(name: &str, config: &WatchConfig) -> Result<Watcher, String> {
+    let inotify = Inotify::init(InitFlags::IN_CLOEXEC | InitFlags::IN_NONBLOCK)
+        .map_err(|err| format!("Failed to initialize inotify: {}", err))?;
+
+    let mut state = WatchState {
+        name: name.to_string(),
+        inotify,
+        dirs: HashMap::new(),
+        paths: config
+            .paths
+            .iter()
+            .map(|glob| glob_to_regex(glob))
+            .collect(),
+        ignore: config
+            .ignore
+            .iter()
+            .map(|glob| glob_to_regex(glob))
+            .collect(),
+    };
+
+    for glob in config.paths.iter() {
+        let (root, depth) = glob_root(glob);
+        state.add_tree(&root, depth);
+    }
+
+    Ok(Watcher {
+        state,
+        debounce: Duration::from_millis(config.debounce_ms),
+        changed: None,
+        registered: false,
+    })
+}
+
+#[test]
+fn converts_globs() {
+    let re = glob_to_regex("src/**/*.rs");
+    assert!(re.is_match("src/main.rs"));
+    assert!(re.is_match("src/a/b/lib.rs"));
+    assert!(!re.is_match("src/main.rsx"));
+    assert!(!re.is_match("tests/main.rs"));
+
+    let re = glob_to_regex("./config/?.toml");
+    assert!(re.is_match("config/a.toml"));
+    assert!(!re.is_match("config/ab.toml"));
+    assert!(!re.is_match("config/a/b.toml"));
+
+    let re = glob_to_regex("**/node_modules/**");
+    assert!(re.is_match("node_modules/"));
+    assert!(re.is_match("web/node_modules/a/b.js"));
+}
+
+#[test]
+fn finds_glob_roots() {
+    assert_eq!(glob_root("src/**/*.rs"), (PathBuf::from("src"), None));
+    assert_eq!(
+        glob_root("config/app.toml"),
+        (PathBuf::from("config"), Some(0))
+    );
+    assert_eq!(glob_root("*.toml"), (PathBuf::from("."), Some(0)));
+    assert_eq!(
+        glob_root("/etc/app/*.conf"),
+        (PathBuf::from("/etc/app"), Some(0))
+    );
+    assert_eq!(
+        glob_root("sites/*/conf/*.toml"),
+        (PathBuf::from("sites"), Some(2))
+    );
+    assert_eq!(glob_root("**/*.py"), (PathBuf::from("."), None));
+}
@@ -1,9 +1,9 @@
 use nix::sys::signal::{kill, Signal};
 use regex::Regex;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, Read, Write};
 use std::process::{ChildStdout, Command, Stdio};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 fn run_multip(args: Vec<&str>) -> Command {
     let mut cmd = Command::new("target/debug/multip");
@@ -166,3 +166,880 @@ fn signal_handling() {
     assert_has_line(&lines, "[test] got signal 2");
     assert_has_line(&lines, "[test] got signal 15");
 }
+
+#[test]
+fn filters_and_redacts_lines() {
+    let mut cmd = run_multip(vec!["--config", "tests/configs/filter.toml"])
+        .spawn()
+        .unwrap();
+
+    let lines = get_lines(cmd.stdout.take());
+    cmd.wait().unwrap();
+
+    assert_has_line(&lines, "[web] GET /login?token=*** 200");
+    assert_line_matches(&lines, r"healthz", 0);
+    assert_line_matches(&lines, r"abc123", 0);
+}
+
+#[test]
+fn redacts_before_splitting_long_lines() {
+    // The token would be split between the parts at 20 bytes
+    let mut cmd = run_multip(vec!["--config", "tests/configs/filter.toml"])
+        .env("MULTIP_MAX_LINE_LENGTH", "20")
+        .spawn()
+        .unwrap();
+
+    let lines = get_lines(cmd.stdout.take());
+    cmd.wait().unwrap();
+
+    assert_line_matches(&lines, r"token=\*\*\*$", 1);
+    assert_line_matches(&lines, r"abc|123", 0);
+}
+
+#[test]
+fn invalid_config_fails_to_start() {
+    let status = run_multip(vec!["--config", "tests/configs/does-not-exist.toml"])
+        .status()
+        .unwrap();
+
+    assert_eq!(status.code(), Some(1));
+}
+
+#[test]
+fn groups_multiline_events() {
+    let mut cmd = run_multip(vec!["--config", "tests/configs/multiline.toml"])
+        .spawn()
+        .unwrap();
+
+    let lines = get_lines(cmd.stdout.take());
+    cmd.wait().unwrap();
+
+    assert_has_line(&lines, "[app] Traceback: |   File foo |   File bar");
+    assert_has_line(&lines, "[app] Error: boom");
+}
+
+#[test]
+fn json_output() {
+    let mut cmd = run_multip(vec![
+        r#"foo: sh -c 'echo hello && echo "{\"level\":\"warn\"}" >&2'"#,
+    ])
+    .env("MULTIP_OUTPUT", "json")
+    .spawn()
+    .unwrap();
+
+    let lines = get_lines(cmd.stdout.take());
+    cmd.wait().unwrap();
+
+    assert_line_matches(
+        &lines,
+        r#"^\{"process":"foo","pid":\d+,"stream":"stdout","message":"hello"\}$"#,
+        1,
+    );
+    assert_line_matches(
+        &lines,
+        r#"^\{"process":"foo","pid":\d+,"stream":"stderr","level":"warn"\}$"#,
+        1,
+    );
+    assert_line_matches(
+        &lines,
+        r#"^\{"process":"multip","pid":\d+,"message":"Started multip"#,
+        1,
+    );
+}
+
+#[test]
+fn invalid_utf8_modes() {
+    let mut cmd = run_multip(vec!["--config", "tests/configs/invalid_utf8.toml"])
+        .spawn()
+        .unwrap();
+
+    let mut out = Vec::new();
+    cmd.stdout.take().unwrap().read_to_end(&mut out).unwrap();
+    cmd.wait().unwrap();
+
+    let lines: Vec<String> = String::from_utf8_lossy(&out)
+        .lines()
+        .map(|line| line.to_string())
+        .collect();
+
+    assert_has_line(&lines, "<strict> Error: stream did not contain valid UTF-8");
+    assert_has_line(&lines, "[lossy] caf\u{FFFD}");
+    assert_has_line(&lines, "[escape] caf\\xE9");
+    assert!(out
+        .windows(b"[raw] caf\xe9\n".len())
+        .any(|w| w == b"[raw] caf\xe9\n"));
+}
+
+#[test]
+fn long_line_policies_and_idle_flush() {
+    let mut cmd = run_multip(vec!["--config", "tests/configs/long_lines.toml"])
+        .spawn()
+        .unwrap();
+
+    let lines = get_lines(cmd.stdout.take());
+    cmd.wait().unwrap();
+
+    assert_has_line(&lines, "[truncate] 12345…(5 bytes dropped)");
+    assert_has_line(&lines, "[unlimited] 1234567890");
+    assert_has_line(&lines, "[prompt...] prompt>");
+}
+
+#[test]
+fn passthrough_output() {
+    let mut cmd = run_multip(vec!["--config", "tests/configs/passthrough.toml"])
+        .spawn()
+        .unwrap();
+
+    let stderr = cmd.stderr.take();
+    let lines = get_lines(cmd.stdout.take());
+    cmd.wait().unwrap();
+
+    assert_has_line(&lines, "copied");
+    assert_has_line(&lines, "inherited");
+    assert_has_line(&lines, "[prefixed] prefixed");
+
+    let mut err = String::new();
+    stderr.unwrap().read_to_string(&mut err).unwrap();
+    assert_eq!(err, "copied-err\n");
+}
+
+#[test]
+fn drops_lines_when_output_buffer_is_full() {
+    let mut cmd = run_multip(vec!["--config", "tests/configs/buffer_full.toml"])
+        .env("MULTIP_BUFFER_LINES", "10")
+        .spawn()
+        .unwrap();
+
+    // Stall the reading so the multip stdout fills up
+    thread::sleep(Duration::from_millis(500));
+
+    let lines = get_lines(cmd.stdout.take());
+    cmd.wait().unwrap();
+
+    let re = Regex::new(r"^Dropped (\d+) lines from \[seq\]").unwrap();
+    let dropped: usize = lines
+        .iter()
+        .filter_map(|line| re.captures(line))
+        .map(|caps| caps[1].parse::<usize>().unwrap())
+        .sum();
+    let printed = lines
+        .iter()
+        .filter(|line| line.starts_with("[seq]"))
+        .count();
+
+    assert!(dropped > 0);
+    assert_eq!(dropped + printed, 100000);
+}
+
+#[test]
+fn handles_signals_while_output_floods() {
+    let mut cmd = run_multip(vec!["--config", "tests/configs/flood.toml"])
+        .env("MULTIP_BUFFER_LINES", "10")
+        .spawn()
+        .unwrap();
+
+    thread::sleep(Duration::from_millis(500));
+    kill(nix::unistd::Pid::from_raw(cmd.id() as i32), Signal::SIGTERM).unwrap();
+    let sent = Instant::now();
+
+    let lines = get_lines(cmd.stdout.take());
+    cmd.wait().unwrap();
+
+    assert!(sent.elapsed() < Duration::from_secs(3));
+    assert_has_line(&lines, "[ticker] ready");
+    assert_has_line(&lines, "[ticker] got TERM");
+}
+
+fn run_ctl(socket: &str, args: Vec<&str>) -> (bool, String) {
+    let out = Command::new("target/debug/multip")
+        .args(vec!["ctl", "--socket", socket])
+        .args(args)
+        .output()
+        .unwrap();
+
+    let text = String::from_utf8_lossy(&out.stdout) + String::from_utf8_lossy(&out.stderr);
+    (out.status.success(), text.into_owned())
+}
+
+fn wait_for_status(socket: &str, pat: &str) -> String {
+    let re = Regex::new(pat).unwrap();
+
+    for _ in 0..50 {
+        let (_, status) = run_ctl(socket, vec!["status"]);
+        if re.is_match(&status) {
+            return status;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    panic!("Status did not match {}", pat);
+}
+
+#[test]
+fn control_socket() {
+    let socket = format!(
+        "{}/multip-test-{}.sock",
+        env!("CARGO_TARGET_TMPDIR"),
+        std::process::id()
+    );
+
+    let mut cmd = run_multip(vec!["a: sleep 10", "b: sleep 10"])
+        .env("MULTIP_CONTROL_SOCKET", &socket)
+        .spawn()
+        .unwrap();
+
+    wait_for_status(&socket, r"(?m)^b +\d+ running ");
+
+    let (ok, out) = run_ctl(&socket, vec!["stop", "a"]);
+    assert!(ok, "{}", out);
+    wait_for_status(
+        &socket,
+        r"(?m)^a +- stopped +- +0 +-?\d+ +\d+\.\d\ds +[\d.]+[KM] +\d+$",
+    );
+
+    let (ok, out) = run_ctl(&socket, vec!["stop", "a"]);
+    assert!(!ok);
+    assert_eq!(out, "a is not running\n");
+
+    let (ok, out) = run_ctl(&socket, vec!["start", "a"]);
+    assert!(ok, "{}", out);
+    wait_for_status(
+        &socket,
+        r"(?m)^a +\d+ running +\d+s +1 +-?\d+ +\d+\.\d\ds +[\d.]+[KM] +\d+$",
+    );
+
+    let (ok, out) = run_ctl(&socket, vec!["restart", "b"]);
+    assert!(ok, "{}", out);
+    wait_for_status(&socket, r"(?m)^b +\d+ running +\d+s +1 ");
+
+    let (ok, out) = run_ctl(&socket, vec!["stop", "nope"]);
+    assert!(!ok);
+    assert_eq!(out, "No such process: nope\n");
+
+    // Signals are sent as is so this brings everything down
+    let (ok, out) = run_ctl(&socket, vec!["signal", "a", "KILL"]);
+    assert!(ok, "{}", out);
+
+    let lines = get_lines(cmd.stdout.take());
+    cmd.wait().unwrap();
+
+    assert_line_matches(&lines, r"^Child a\(\d+\) stopped with exit code", 1);
+    assert_line_matches(&lines, r"^Child b\(\d+\) stopped with exit code", 1);
+    assert_line_matches(&lines, r"^Child a\(\d+\) died with exit code", 1);
+}
+
+#[test]
+fn keeps_files_at_the_socket_path() {
+    let path = format!(
+        "{}/multip-not-a-socket-{}",
+        env!("CARGO_TARGET_TMPDIR"),
+        std::process::id()
+    );
+    std::fs::write(&path, "keep me").unwrap();
+
+    let output = run_multip(vec!["a: sleep 10"])
+        .env("MULTIP_CONTROL_SOCKET", &path)
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        format!("Cannot listen on {}: not a socket\n", path)
+    );
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "keep me");
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn control_socket_logs() {
+    let socket = format!(
+        "{}/multip-logs-test-{}.sock",
+        env!("CARGO_TARGET_TMPDIR"),
+        std::process::id()
+    );
+
+    let mut cmd = run_multip(vec![
+        "a: sh -c 'echo a1; echo a2; exec sleep 10'",
+        "b: sh -c 'while true; do echo tick; sleep 0.05; done'",
+    ])
+    .env("MULTIP_CONTROL_SOCKET", &socket)
+    .spawn()
+    .unwrap();
+
+    wait_for_status(&socket, r"(?m)^b +\d+ running ");
+    thread::sleep(Duration::from_millis(200));
+
+    let (ok, out) = run_ctl(&socket, vec!["logs", "a"]);
+    assert!(ok, "{}", out);
+    assert_eq!(out, "[a] a1\n[a] a2\n");
+
+    let (ok, out) = run_ctl(&socket, vec!["logs", "nope"]);
+    assert!(!ok);
+    assert_eq!(out, "No such process: nope\n");
+
+    let mut follow = Command::new("target/debug/multip")
+        .args(vec!["ctl", "--socket", &socket, "logs", "--follow", "b"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // Read past the recent lines to get the streamed ones too
+    let mut follow_lines = BufReader::new(follow.stdout.take().unwrap()).lines();
+    for _ in 0..20 {
+        assert_eq!(follow_lines.next().unwrap().unwrap(), "[b] tick");
+    }
+
+    run_ctl(&socket, vec!["signal", "a", "KILL"]);
+    get_lines(cmd.stdout.take());
+    cmd.wait().unwrap();
+
+    // The connection is closed when multip exits
+    assert!(follow_lines.all(|line| line.unwrap() == "[b] tick"));
+    follow.wait().unwrap();
+}
+
+#[test]
+fn reloads_config_on_sighup() {
+    let dir = env!("CARGO_TARGET_TMPDIR");
+    let socket = format!("{}/multip-reload-test-{}.sock", dir, std::process::id());
+    let config = format!("{}/multip-reload-test-{}.toml", dir, std::process::id());
+
+    let write_config = |processes: &[(&str, &str)]| {
+        let toml: String = processes
+            .iter()
+            .map(|(name, command)| {
+                format!(
+                    "[[process]]\nname = \"{}\"\ncommand = \"{}\"\n",
+                    name, command
+                )
+            })
+            .collect();
+        std::fs::write(&config, toml).unwrap();
+    };
+
+    write_config(&[("a", "sleep 10"), ("b", "sleep 10"), ("c", "sleep 10")]);
+
+    let mut cmd = run_multip(vec!["--config", &config])
+        .env("MULTIP_CONTROL_SOCKET", &socket)
+        .spawn()
+        .unwrap();
+    let pid = nix::unistd::Pid::from_raw(cmd.id() as i32);
+
+    let status = wait_for_status(&socket, r"(?m)^c +\d+ running ");
+    let a_pid = Regex::new(r"(?m)^a +(\d+) ")
+        .unwrap()
+        .captures(&status)
+        .unwrap()[1]
+        .to_string();
+
+    write_config(&[("a", "sleep 10"), ("b", "sleep 11"), ("d", "sleep 10")]);
+    kill(pid, Signal::SIGHUP).unwrap();
+
+    let status = wait_for_status(&socket, r"(?m)^d +\d+ running ");
+    wait_for_status(&socket, r"(?m)^b +\d+ running +\d+s +1 ");
+    assert!(Regex::new(&format!(r"(?m)^a +{} running +\d+s +0 ", a_pid))
+        .unwrap()
+        .is_match(&status));
+
+    // Removed processes are forgotten once they have stopped
+    for _ in 0..50 {
+        let (_, status) = run_ctl(&socket, vec!["status"]);
+        if !status.lines().any(|line| line.starts_with("c ")) {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    let (_, status) = run_ctl(&socket, vec!["status"]);
+    assert!(!status.lines().any(|line| line.starts_with("c ")));
+
+    // Invalid config keeps the current processes running
+    std::fs::write(&config, "[[process]]\nname = \"a\"\n").unwrap();
+    kill(pid, Signal::SIGHUP).unwrap();
+    thread::sleep(Duration::from_millis(200));
+    wait_for_status(&socket, r"(?m)^d +\d+ running ");
+
+    run_ctl(&socket, vec!["signal", "a", "KILL"]);
+
+    let lines = get_lines(cmd.stdout.take());
+    cmd.wait().unwrap();
+
+    assert_has_line(&lines, "Stopping [c] removed from the config");
+    assert_has_line(&lines, "Starting [d] added to the config");
+    assert_has_line(&lines, "Restarting [b] because its config changed");
+    assert_line_matches(&lines, r"^Config reload failed: ", 1);
+}
+
+#[test]
+fn routes_signals() {
+    let mut cmd = run_multip(vec!["--config", "tests/configs/signals.toml"])
+        .spawn()
+        .unwrap();
+
+    let pid = nix::unistd::Pid::from_raw(cmd.id() as i32);
+
+    thread::sleep(Duration::from_millis(200));
+    kill(pid, Signal::SIGHUP).unwrap();
+    thread::sleep(Duration::from_millis(200));
+    kill(pid, Signal::SIGUSR2).unwrap();
+    thread::sleep(Duration::from_millis(200));
+    kill(pid, Signal::SIGTERM).unwrap();
+
+    let lines = get_lines(cmd.stdout.take());
+    cmd.wait().unwrap();
+
+    assert_has_line(&lines, "[web] got HUP");
+    assert_has_line(&lines, "[web] got USR2");
+    assert_has_line(&lines, "[web] got QUIT");
+    assert_has_line(&lines, "[shipper] got TERM");
+    assert_line_matches(&lines, r"^\[shipper\] got (HUP|USR2)", 0);
+}
+
+#[test]
+fn routes_shutdown_signal() {
+    let config = format!(
+        "{}/multip-shutdown-test-{}.toml",
+        env!("CARGO_TARGET_TMPDIR"),
+        std::process::id()
+    );
+    std::fs::write(
+        &config,
+        r#"
+[[process]]
+name = "web"
+command = '''sh -c 'trap "echo got QUIT; exit 0" QUIT; while true; do sleep 0.05; done' '''
+
+[process.signals]
+TERM = "QUIT"
+
+[[process]]
+name = "job"
+command = "sleep 0.3"
+"#,
+    )
+    .unwrap();
+
+    let mut cmd = run_multip(vec!["--config", &config]).spawn().unwrap();
+    let lines = get_lines(cmd.stdout.take());
+    cmd.wait().unwrap();
+    std::fs::remove_file(&config).unwrap();
+
+    assert_has_line(&lines, "[web] got QUIT");
+    assert_line_matches(&lines, r"^Sending SIGQUIT to web\(\d+\)$", 1);
+    assert_line_matches(&lines, r"^Sending SIGTERM to web", 0);
+}
+
+#[test]
+fn signals_whole_process_group() {
+    let mut cmd = run_multip(vec!["--config", "tests/configs/process_group.toml"])
+        .spawn()
+        .unwrap();
+
+    let lines = get_lines(cmd.stdout.take());
+    cmd.wait().unwrap();
+
+    assert_line_matches(
+        &lines,
+        r"^Sending SIGTERM to process group of forker\(\d+\)",
+        1,
+    );
+
+    let worker = lines
+        .iter()
+        .find_map(|line| line.strip_prefix("[forker] worker "))
+        .expect("worker pid");
+
+    // The worker must be gone or waiting to be reaped by init
+    thread::sleep(Duration::from_millis(100));
+    if let Ok(stat) = std::fs::read_to_string(format!("/proc/{}/stat", worker)) {
+        assert!(stat.contains(") Z "), "worker still running: {}", stat);
+    }
+}
+
+#[test]
+fn stops_stray_descendants_before_exiting() {
+    let started = std::time::Instant::now();
+    let mut cmd = run_multip(vec!["--config", "tests/configs/strays.toml"])
+        .env("MULTIP_STRAY_TIMEOUT_MS", "300")
+        .spawn()
+        .unwrap();
+
+    let lines = get_lines(cmd.stdout.take());
+    cmd.wait().unwrap();
+
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert_line_matches(&lines, r"^Sending SIGTERM to stray process sleep\(\d+\)", 2);
+    assert_line_matches(
+        &lines,
+        r"^Killing stray process sleep\(\d+\) with SIGKILL",
+        1,
+    );
+}
+
+#[test]
+fn counts_every_sigint() {
+    let mut cmd = run_multip(vec![
+        "test: sh -c 'trap \"echo got INT\" INT; trap \"echo got TERM\" TERM; for i in $(seq 40); do sleep 0.05; done'",
+    ])
+    .spawn()
+    .unwrap();
+
+    let pid = nix::unistd::Pid::from_raw(cmd.id() as i32);
+
+    thread::sleep(Duration::from_millis(100));
+
+    // The kernel merges pending signals of the same kind so leave a small gap
+    for _ in 0..3 {
+        kill(pid, Signal::SIGINT).unwrap();
+        thread::sleep(Duration::from_millis(5));
+    }
+
+    let lines = get_lines(cmd.stdout.take());
+    cmd.wait().unwrap();
+
+    assert_has_line(&lines, "Got second SIGINT, converting it to SIGKILL");
+    assert_has_line(&lines, "Got third SIGINT, converting it to SIGKILL");
+}
+
+#[test]
+fn logs_resource_usage_on_exit() {
+    let mut cmd = run_multip(vec![
+        "a: sh -c 'i=0; while [ $i -lt 1000 ]; do i=$((i+1)); done'",
+    ])
+    .spawn()
+    .unwrap();
+
+    let lines = get_lines(cmd.stdout.take());
+    cmd.wait().unwrap();
+
+    assert_line_matches(
+        &lines,
+        r"^Resource usage of a\(\d+\): cpu \d+\.\d\ds \(user \d+\.\d\ds, system \d+\.\d\ds\), max rss [1-9]\d* KiB, context switches \d+ voluntary \d+ involuntary$",
+        1,
+    );
+}
+
+fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Status code and body of the response
+fn http_get(addr: &str, path: &str) -> Option<(u16, String)> {
+    let mut stream = std::net::TcpStream::connect(addr).ok()?;
+    write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, addr).ok()?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).ok()?;
+
+    let (head, body) = response.split_at(response.find("\r\n\r\n")? + 4);
+    let status = head.split_whitespace().nth(1)?.parse().ok()?;
+    Some((status, body.to_string()))
+}
+
+fn wait_for_http(addr: &str, path: &str, pat: &str) -> (u16, String) {
+    let re = Regex::new(pat).unwrap();
+
+    for _ in 0..50 {
+        if let Some((status, body)) = http_get(addr, path) {
+            if re.is_match(&body) {
+                return (status, body);
+            }
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    panic!("{} did not match {}", path, pat);
+}
+
+#[test]
+fn serves_metrics() {
+    let addr = format!("127.0.0.1:{}", free_port());
+
+    let mut cmd = run_multip(vec!["a: sh -c 'echo hello; exec sleep 10'", "b: sleep 10"])
+        .env("MULTIP_HTTP_LISTEN", &addr)
+        .spawn()
+        .unwrap();
+
+    let (status, body) = wait_for_http(
+        &addr,
+        "/metrics",
+        r#"(?m)^multip_lines_emitted_total\{process="a",stream="stdout"\} 1$"#,
+    );
+    assert_eq!(status, 200);
+    assert!(body.contains("\nmultip_process_up{process=\"a\"} 1\n"));
+    assert!(body.contains("\nmultip_process_restarts_total{process=\"b\"} 0\n"));
+    assert!(body.contains("\nmultip_zombies_reaped_total 0\n"));
+
+    assert_eq!(
+        http_get(&addr, "/nope").map(|(status, _)| status),
+        Some(404)
+    );
+
+    kill(nix::unistd::Pid::from_raw(cmd.id() as i32), Signal::SIGTERM).unwrap();
+    cmd.wait().unwrap();
+}
+
+#[test]
+fn serves_health_checks() {
+    let addr = format!("127.0.0.1:{}", free_port());
+
+    let mut cmd = run_multip(vec!["--config", "tests/configs/ready.toml"])
+        .env("MULTIP_HTTP_LISTEN", &addr)
+        .spawn()
+        .unwrap();
+
+    let (status, body) = wait_for_http(&addr, "/readyz", r#""status":"not ready""#);
+    assert_eq!(status, 503);
+    assert!(body.contains(r#""worker":{"state":"running","#), "{}", body);
+
+    let (status, body) = wait_for_http(&addr, "/readyz", r#""status":"ready""#);
+    assert_eq!(status, 200);
+    assert!(body.contains(r#""web":{"state":"running","#), "{}", body);
+
+    let (status, _) = wait_for_http(&addr, "/healthz", r#""status":"ok""#);
+    assert_eq!(status, 200);
+
+    kill(nix::unistd::Pid::from_raw(cmd.id() as i32), Signal::SIGTERM).unwrap();
+    cmd.wait().unwrap();
+}
+
+#[test]
+fn checks_config() {
+    let out = run_multip(vec!["check", "--config", "tests/configs/signals.toml"])
+        .output()
+        .unwrap();
+    let lines: Vec<String> = String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .map(String::from)
+        .collect();
+
+    assert!(out.status.success());
+    assert_has_line(&lines, "All 2 processes are valid");
+
+    let out = run_multip(vec![
+        "check",
+        "--config",
+        "tests/configs/check_invalid.toml",
+    ])
+    .output()
+    .unwrap();
+    let lines: Vec<String> = String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .map(String::from)
+        .collect();
+
+    assert_eq!(out.status.code(), Some(1));
+    assert_has_line(
+        &lines,
+        "Invalid command for [web]: surely-not-a-command not found in PATH",
+    );
+    assert_has_line(
+        &lines,
+        "Invalid stop_signal for [worker]: Unknown signal SIGNOPE",
+    );
+    assert_has_line(&lines, "Duplicate process name [worker]");
+    assert_has_line(&lines, "Found 3 problems in 3 processes");
+}
+
+#[test]
+fn names_unnamed_commands() {
+    let mut cmd = run_multip(vec![
+        "a: sh -c 'sleep 0.5; echo first'",
+        "sh -c 'echo second; exec sleep 10'",
+        "sh -c 'echo x:y; exec sleep 10'",
+    ])
+    .spawn()
+    .unwrap();
+
+    let lines = get_lines(cmd.stdout.take());
+    cmd.wait().unwrap();
+
+    assert_has_line(&lines, "[a] first");
+    assert_has_line(&lines, "[cmd1] second");
+    assert_has_line(&lines, "[cmd2] x:y");
+}
+
+#[test]
+fn rejects_invalid_names() {
+    let out = run_multip(vec!["a: true", "a: true"]).output().unwrap();
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8_lossy(&out.stderr),
+        "Duplicate process name [a]\n"
+    );
+
+    let out = run_multip(vec!["web@1: true"]).output().unwrap();
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8_lossy(&out.stderr),
+        "Invalid process name \"web@1\": use only letters, digits, '_', '-' and '.'\n"
+    );
+}
+
+#[test]
+fn runs_instances() {
+    let socket = format!(
+        "{}/multip-instances-test-{}.sock",
+        env!("CARGO_TARGET_TMPDIR"),
+        std::process::id()
+    );
+
+    let mut cmd = run_multip(vec![
+        "--config",
+        "tests/configs/instances.toml",
+        "cli*2: sh -c 'echo cli $MULTIP_INSTANCE; exec sleep 10'",
+    ])
+    .env("MULTIP_CONTROL_SOCKET", &socket)
+    .spawn()
+    .unwrap();
+
+    wait_for_status(&socket, r"(?m)^cli\.2 +\d+ running ");
+
+    let (ok, out) = run_ctl(&socket, vec!["restart", "worker.2"]);
+    assert!(ok, "{}", out);
+    let status = wait_for_status(&socket, r"(?m)^worker\.2 +\d+ running +\d+s +1 ");
+    assert!(Regex::new(r"(?m)^worker\.1 +\d+ running +\d+s +0 ")
+        .unwrap()
+        .is_match(&status));
+
+    kill(nix::unistd::Pid::from_raw(cmd.id() as i32), Signal::SIGTERM).unwrap();
+
+    let lines = get_lines(cmd.stdout.take());
+    cmd.wait().unwrap();
+
+    assert_has_line(&lines, "[worker.1] instance 1 port 8000");
+    assert_has_line(&lines, "[worker.3] instance 3 port 8002");
+    assert_line_matches(&lines, r"^\[worker\.2\] instance 2 port 8001$", 2);
+    assert_has_line(&lines, "[cli.1] cli 1");
+    assert_has_line(&lines, "[cli.2] cli 2");
+}
+
+#[test]
+fn runs_scheduled_jobs() {
+    let mut cmd = run_multip(vec!["--config", "tests/configs/jobs.toml"])
+        .spawn()
+        .unwrap();
+
+    let lines = get_lines(cmd.stdout.take());
+    let status = cmd.wait().unwrap();
+
+    // Failing jobs do not bring the service down
+    assert!(status.success());
+    assert_line_matches(&lines, r"^Child service\(\d+\) died with exit code 0$", 1);
+    assert!(lines.iter().filter(|line| *line == "[tick] tick").count() >= 2);
+    assert!(lines.iter().any(
+        |line| Regex::new(r"^Job broken\(\d+\) failed with exit code 3$")
+            .unwrap()
+            .is_match(line)
+    ));
+    assert!(lines.iter().any(|line| Regex::new(
+        r"^Skipping job \[slow\] because slow\(\d+\) is still running$"
+    )
+    .unwrap()
+    .is_match(line)));
+}
+
+#[test]
+fn starts_jobs_turned_into_services() {
+    let dir = env!("CARGO_TARGET_TMPDIR");
+    let socket = format!("{}/multip-unschedule-test-{}.sock", dir, std::process::id());
+    let config = format!("{}/multip-unschedule-test-{}.toml", dir, std::process::id());
+
+    let write_config = |job: &str| {
+        std::fs::write(
+            &config,
+            format!(
+                "[[process]]\nname = \"service\"\ncommand = \"sleep 10\"\n\n\
+                 [[process]]\nname = \"job\"\n{}\n",
+                job
+            ),
+        )
+        .unwrap();
+    };
+
+    write_config("command = \"true\"\nschedule = \"* * * * * *\"");
+
+    let mut cmd = run_multip(vec!["--config", &config])
+        .env("MULTIP_CONTROL_SOCKET", &socket)
+        .spawn()
+        .unwrap();
+    let pid = nix::unistd::Pid::from_raw(cmd.id() as i32);
+
+    // The first run has finished
+    wait_for_status(&socket, r"(?m)^job +- exited ");
+
+    write_config("command = \"sleep 10\"");
+    kill(pid, Signal::SIGHUP).unwrap();
+    wait_for_status(&socket, r"(?m)^job +\d+ running ");
+
+    kill(pid, Signal::SIGTERM).unwrap();
+    cmd.wait().unwrap();
+    std::fs::remove_file(&config).unwrap();
+}
+
+#[test]
+fn restarts_on_file_changes() {
+    let dir = format!(
+        "{}/multip-watch-test-{}",
+        env!("CARGO_TARGET_TMPDIR"),
+        std::process::id()
+    );
+    let socket = format!("{}.sock", dir);
+    std::fs::create_dir_all(format!("{}/ignored", dir)).unwrap();
+
+    let config = format!(
+        r#"
+[[process]]
+name = "app"
+command = "sh -c 'echo started; exec sleep 10'"
+watch = {{ paths = ["{dir}/**/*.txt"], ignore = ["{dir}/ignored/**"], debounce_ms = 100 }}
+
+[[process]]
+name = "other"
+command = "sleep 10"
+"#,
+        dir = dir
+    );
+    let config_path = format!("{}.toml", dir);
+    std::fs::write(&config_path, config).unwrap();
+
+    let mut cmd = run_multip(vec!["--config", &config_path])
+        .env("MULTIP_CONTROL_SOCKET", &socket)
+        .spawn()
+        .unwrap();
+
+    wait_for_status(&socket, r"(?m)^other +\d+ running ");
+
+    // Not matching or ignored
+    std::fs::write(format!("{}/notes.md", dir), "x").unwrap();
+    std::fs::write(format!("{}/ignored/a.txt", dir), "x").unwrap();
+    thread::sleep(Duration::from_millis(300));
+
+    // Directories created later are watched too
+    std::fs::create_dir(format!("{}/sub", dir)).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    std::fs::write(format!("{}/sub/b.txt", dir), "x").unwrap();
+
+    let status = wait_for_status(&socket, r"(?m)^app +\d+ running +\d+s +1 ");
+    assert!(Regex::new(r"(?m)^other +\d+ running +\d+s +0 ")
+        .unwrap()
+        .is_match(&status));
+
+    kill(nix::unistd::Pid::from_raw(cmd.id() as i32), Signal::SIGTERM).unwrap();
+
+    let lines = get_lines(cmd.stdout.take());
+    cmd.wait().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    std::fs::remove_file(&config_path).unwrap();
+
+    assert_line_matches(&lines, r"^\[app\] started$", 2);
+    assert_line_matches(
+        &lines,
+        r"^Restarting app\(\d+\) because .*/sub/b\.txt changed$",
+        1,
+    );
+}